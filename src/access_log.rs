@@ -0,0 +1,357 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use signal_hook::consts::SIGHUP;
+use signal_hook::flag;
+
+use crate::log::error;
+use crate::pages::escape_json;
+use crate::request::Request;
+use crate::time::{clf_date, rfc3339};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Common Log Format
+    Common,
+    /// Common Log Format with referer and user agent
+    Combined,
+    /// Combined Log Format with redirect target length and latency
+    CombinedExt,
+    /// JSON object per line
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            "combined_ext" => Ok(LogFormat::CombinedExt),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {value}")),
+        }
+    }
+}
+
+/// What is known about a handled request
+pub struct Entry<'a> {
+    /// Client address, Unix sockets have none
+    pub peer: Option<IpAddr>,
+    /// Time the request was received
+    pub time: SystemTime,
+    /// Request, unless it could not be parsed
+    pub request: Option<&'a Request>,
+    pub status: u16,
+    /// Number of body bytes sent
+    pub bytes: usize,
+    /// Redirect target
+    pub location: Option<&'a str>,
+    /// Time from receiving the request to sending the response
+    pub latency: Duration,
+}
+
+/// Quotes text for Common Log Format, escaping quotes and control characters
+fn quote(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(ch);
+            }
+            ch if ch.is_control() => quoted.push_str(&format!("\\x{:02x}", ch as u32)),
+            _ => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+impl Entry<'_> {
+    fn peer(&self) -> String {
+        match self.peer {
+            Some(ip) => ip.to_string(),
+            None => "-".to_string(),
+        }
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.request.and_then(|request| request.headers.get(name))
+    }
+
+    fn request_line(&self) -> String {
+        match self.request {
+            Some(request) if request.version.is_empty() => {
+                format!("{} {}", request.method, request.target)
+            }
+            Some(request) => format!("{} {} {}", request.method, request.target, request.version),
+            None => "-".to_string(),
+        }
+    }
+
+    /// Fields of Common Log Format
+    fn clf(&self) -> String {
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        format!(
+            "{} - - [{}] {} {} {bytes}",
+            self.peer(),
+            clf_date(self.time),
+            quote(&self.request_line()),
+            self.status
+        )
+    }
+
+    fn combined(&self) -> String {
+        let quote_header = |name| match self.header(name) {
+            Some(value) => quote(value),
+            None => "\"-\"".to_string(),
+        };
+        format!(
+            "{} {} {}",
+            self.clf(),
+            quote_header("Referer"),
+            quote_header("User-Agent")
+        )
+    }
+
+    /// Combined Log Format followed by redirect target length and latency
+    /// in microseconds, like Apache's `%D`
+    fn combined_ext(&self) -> String {
+        let location_length = match self.location {
+            Some(location) => location.len().to_string(),
+            None => "-".to_string(),
+        };
+        format!(
+            "{} {location_length} {}",
+            self.combined(),
+            self.latency.as_micros()
+        )
+    }
+
+    fn json(&self) -> String {
+        let json_string = |value: Option<&str>| match value {
+            Some(value) => escape_json(value),
+            None => "null".to_string(),
+        };
+        let request = self.request;
+        format!(
+            concat!(
+                r#"{{"time":{},"peer":{},"method":{},"target":{},"protocol":{},"status":{},"#,
+                r#""request_bytes":{},"bytes":{},"location_length":{},"latency_ms":{:.3},"#,
+                r#""referer":{},"user_agent":{}}}"#
+            ),
+            escape_json(&rfc3339(self.time)),
+            json_string(self.peer.map(|ip| ip.to_string()).as_deref()),
+            json_string(request.map(|request| request.method.as_str())),
+            json_string(request.map(|request| request.target.as_str())),
+            json_string(request.map(|request| request.version.as_str())),
+            self.status,
+            match request {
+                Some(request) => request.body.len().to_string(),
+                None => "null".to_string(),
+            },
+            self.bytes,
+            match self.location {
+                Some(location) => location.len().to_string(),
+                None => "null".to_string(),
+            },
+            self.latency.as_secs_f64() * 1000.0,
+            json_string(self.header("Referer")),
+            json_string(self.header("User-Agent")),
+        )
+    }
+
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Common => self.clf(),
+            LogFormat::Combined => self.combined(),
+            LogFormat::CombinedExt => self.combined_ext(),
+            LogFormat::Json => self.json(),
+        }
+    }
+}
+
+enum Sink {
+    Stderr,
+    File(PathBuf, File),
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Writes a line per request to stderr or a file.
+/// The file is reopened on SIGHUP, so it may be rotated.
+pub struct AccessLog {
+    format: LogFormat,
+    sink: Mutex<Sink>,
+    reopen: Arc<AtomicBool>,
+}
+
+impl AccessLog {
+    /// Opens the log file, logs to stderr if there is no `path`
+    pub fn open(path: Option<&Path>, format: LogFormat) -> Result<AccessLog, String> {
+        let sink = match path {
+            Some(path) => Sink::File(
+                path.to_path_buf(),
+                open(path).map_err(|err| format!("{}: {err}", path.display()))?,
+            ),
+            None => Sink::Stderr,
+        };
+        Ok(AccessLog {
+            format,
+            sink: Mutex::new(sink),
+            reopen: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Reopens the file on SIGHUP
+    pub fn register(&self) -> io::Result<()> {
+        flag::register(SIGHUP, self.reopen.clone()).map(|_| ())
+    }
+
+    pub fn write(&self, entry: &Entry) {
+        let line = entry.format(self.format);
+        let mut sink = match self.sink.lock() {
+            Ok(sink) => sink,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        match &mut *sink {
+            Sink::Stderr => eprintln!("{line}"),
+            Sink::File(path, file) => {
+                if self.reopen.swap(false, Ordering::Relaxed) {
+                    match open(path) {
+                        Ok(reopened) => *file = reopened,
+                        Err(err) => error!(
+                            "Failed to reopen access log",
+                            path = path.display(),
+                            error = err
+                        ),
+                    }
+                }
+                if let Err(err) = writeln!(file, "{line}") {
+                    error!(
+                        "Failed to write access log",
+                        path = path.display(),
+                        error = err
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::time::UNIX_EPOCH;
+
+    const REQUEST: &str =
+        "GET /search?q=!w+\"a\" HTTP/1.1\r\nReferer: http://a/\r\nUser-Agent: Agent/1.0\r\n\r\n";
+
+    fn entry(request: Option<&Request>) -> Entry<'_> {
+        Entry {
+            peer: Some("127.0.0.1".parse().unwrap()),
+            time: UNIX_EPOCH + Duration::from_secs(971186136),
+            request,
+            status: 303,
+            bytes: 0,
+            location: Some("https://w/a"),
+            latency: Duration::from_micros(1500),
+        }
+    }
+
+    #[test]
+    fn common() {
+        let request = Request::parse(REQUEST.as_bytes()).unwrap();
+        assert_eq!(
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /search?q=!w+\"a\" HTTP/1.1" 303 -"#,
+            entry(Some(&request)).format(LogFormat::Common)
+        );
+    }
+
+    #[test]
+    fn combined() {
+        let request = Request::parse(REQUEST.as_bytes()).unwrap();
+        assert_eq!(
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /search?q=!w+\"a\" HTTP/1.1" 303 - "http://a/" "Agent/1.0""#,
+            entry(Some(&request)).format(LogFormat::Combined)
+        );
+    }
+
+    #[test]
+    fn combined_ext() {
+        let request = Request::parse(REQUEST.as_bytes()).unwrap();
+        assert_eq!(
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 +0000] "GET /search?q=!w+\"a\" HTTP/1.1" 303 - "http://a/" "Agent/1.0" 11 1500"#,
+            entry(Some(&request)).format(LogFormat::CombinedExt)
+        );
+    }
+
+    #[test]
+    fn combined_unparsed() {
+        let mut entry = entry(None);
+        entry.peer = None;
+        entry.status = 400;
+        entry.bytes = 17;
+        entry.location = None;
+        assert_eq!(
+            r#"- - - [10/Oct/2000:13:55:36 +0000] "-" 400 17 "-" "-" - 1500"#,
+            entry.format(LogFormat::CombinedExt)
+        );
+    }
+
+    #[test]
+    fn json() {
+        let request = Request::parse(REQUEST.as_bytes()).unwrap();
+        assert_eq!(
+            concat!(
+                r#"{"time":"2000-10-10T13:55:36Z","peer":"127.0.0.1","method":"GET","#,
+                r#""target":"/search?q=!w+\"a\"","protocol":"HTTP/1.1","status":303,"request_bytes":0,"#,
+                r#""bytes":0,"#,
+                r#""location_length":11,"latency_ms":1.500,"referer":"http://a/","user_agent":"Agent/1.0"}"#
+            ),
+            entry(Some(&request)).format(LogFormat::Json)
+        );
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!(Ok(LogFormat::Json), "json".parse());
+        assert_eq!(Ok(LogFormat::CombinedExt), "combined_ext".parse());
+        assert!("apache".parse::<LogFormat>().is_err());
+    }
+
+    #[test]
+    fn reopen() {
+        let path = env::temp_dir().join(format!("banger-{}-access.log", process::id()));
+        let rotated = path.with_extension("log.1");
+        let log = AccessLog::open(Some(&path), LogFormat::Common).unwrap();
+        log.write(&entry(None));
+        fs::rename(&path, &rotated).unwrap();
+
+        // Rotated file keeps receiving lines until the log is reopened
+        log.write(&entry(None));
+        log.reopen.store(true, Ordering::Relaxed);
+        log.write(&entry(None));
+        assert_eq!(2, fs::read_to_string(&rotated).unwrap().lines().count());
+        assert_eq!(1, fs::read_to_string(&path).unwrap().lines().count());
+        fs::remove_file(path).unwrap();
+        fs::remove_file(rotated).unwrap();
+    }
+}
@@ -0,0 +1,117 @@
+use std::env;
+use std::io;
+use std::mem;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::process;
+
+/// First file descriptor passed by systemd, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Finds the number of sockets passed to this process with `LISTEN_PID` and `LISTEN_FDS`
+fn parse_listen_fds(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    pid: u32,
+) -> Result<usize, String> {
+    let (listen_pid, listen_fds) = match (listen_pid, listen_fds) {
+        (Some(listen_pid), Some(listen_fds)) => (listen_pid, listen_fds),
+        _ => return Ok(0),
+    };
+    // Variables may be inherited from a parent that was activated instead
+    match listen_pid.parse::<u32>() {
+        Ok(listen_pid) if listen_pid == pid => (),
+        Ok(_) => return Ok(0),
+        Err(_err) => return Err(format!("`LISTEN_PID` is not a process ID: {listen_pid}")),
+    }
+    listen_fds
+        .parse::<usize>()
+        .map_err(|_err| format!("`LISTEN_FDS` is not a number: {listen_fds}"))
+}
+
+fn set_cloexec(fd: RawFd) -> io::Result<()> {
+    // SAFETY: fcntl does not access memory, invalid descriptors are reported as errors
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: same as above
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Takes sockets passed by systemd socket activation, if there are any
+pub fn listen_fds() -> Result<Vec<OwnedFd>, String> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+    let count = parse_listen_fds(listen_pid.as_deref(), listen_fds.as_deref(), process::id())?;
+    (0..count)
+        .map(|index| {
+            let fd = LISTEN_FDS_START + index as RawFd;
+            set_cloexec(fd)
+                .map_err(|err| format!("Inherited descriptor {fd} is invalid: {err}"))?;
+            // SAFETY: systemd passes descriptors from 3 to 3 + LISTEN_FDS to this process,
+            // nothing else in the process owns them
+            Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+        })
+        .collect()
+}
+
+/// Gets the address family of a socket, e.g. `AF_INET`
+pub fn socket_family(fd: RawFd) -> io::Result<libc::c_int> {
+    // SAFETY: sockaddr_storage is plain data, zeroed value is valid
+    let mut address: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut length = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: `address` is large enough for any address and `length` holds its size
+    let result = unsafe {
+        libc::getsockname(
+            fd,
+            &mut address as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut length,
+        )
+    };
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(address.ss_family as libc::c_int)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::os::fd::AsRawFd;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn parse_success() {
+        assert_eq!(Ok(2), parse_listen_fds(Some("42"), Some("2"), 42));
+    }
+
+    #[test]
+    fn parse_missing() {
+        assert_eq!(Ok(0), parse_listen_fds(None, None, 42));
+        assert_eq!(Ok(0), parse_listen_fds(Some("42"), None, 42));
+        assert_eq!(Ok(0), parse_listen_fds(None, Some("1"), 42));
+    }
+
+    #[test]
+    fn parse_other_process() {
+        assert_eq!(Ok(0), parse_listen_fds(Some("7"), Some("1"), 42));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert!(parse_listen_fds(Some("pid"), Some("1"), 42).is_err());
+        assert!(parse_listen_fds(Some("42"), Some("-1"), 42).is_err());
+    }
+
+    #[test]
+    fn family() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert_eq!(libc::AF_INET, socket_family(listener.as_raw_fd()).unwrap());
+        let (socket, _other) = UnixDatagram::pair().unwrap();
+        assert_eq!(libc::AF_UNIX, socket_family(socket.as_raw_fd()).unwrap());
+    }
+}
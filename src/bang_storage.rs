@@ -1,11 +1,103 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
 use toml::Table;
 
+use crate::response::StatusCode;
+
+/// Statuses a search may be redirected with
+const REDIRECT_STATUSES: [StatusCode; 5] = [
+    StatusCode::MovedPermanently,
+    StatusCode::Found,
+    StatusCode::SeeOther,
+    StatusCode::TemporaryRedirect,
+    StatusCode::PermanentRedirect,
+];
+
+/// How a search is sent to the query URL
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RedirectMode {
+    /// Redirect status with `Location` header
+    Location,
+    /// Redirect status with `Location` header, the query URL gets no `Referer`
+    NoReferrer,
+    /// HTML page that refreshes to the query URL, which gets no `Referer`
+    Refresh,
+}
+
+impl RedirectMode {
+    const NAMES: [(&str, RedirectMode); 3] = [
+        ("location", RedirectMode::Location),
+        ("no_referrer", RedirectMode::NoReferrer),
+        ("refresh", RedirectMode::Refresh),
+    ];
+}
+
+/// Aliases sorted for prefix lookup, with counters of their use
+#[derive(Debug, Default)]
+pub struct AliasIndex {
+    usage: BTreeMap<String, AtomicU64>,
+}
+
+impl AliasIndex {
+    pub fn new<'a>(aliases: impl Iterator<Item = &'a String>) -> Self {
+        AliasIndex {
+            usage: aliases
+                .map(|alias| (alias.clone(), AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    pub fn record_use(&self, alias: &str) {
+        if let Some(counter) = self.usage.get(alias) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Finds up to `limit` aliases that start with `prefix`,
+    /// most used first, then shortest first
+    pub fn complete(&self, prefix: &str, limit: usize) -> Vec<String> {
+        let mut found: Vec<(&String, u64)> = self
+            .usage
+            .range(prefix.to_string()..)
+            .take_while(|(alias, _)| alias.starts_with(prefix))
+            .map(|(alias, counter)| (alias, counter.load(Ordering::Relaxed)))
+            .collect();
+        found.sort_by_key(|(alias, usage)| (Reverse(*usage), alias.chars().count(), *alias));
+        found
+            .into_iter()
+            .take(limit)
+            .map(|(alias, _)| alias.clone())
+            .collect()
+    }
+}
+
+/// Bang as configured: all of its aliases and the query
+#[derive(Debug, PartialEq)]
+pub struct Bang {
+    pub aliases: Vec<String>,
+    pub query: String,
+    /// Overrides the redirect status of the storage
+    pub redirect_status: Option<StatusCode>,
+    /// Overrides the redirect mode of the storage
+    pub redirect_mode: Option<RedirectMode>,
+}
+
 #[derive(Debug)]
 pub struct BangStorage {
+    /// Queries by aliases
     pub bangs: HashMap<String, String>,
+    /// Bangs in config order
+    pub groups: Vec<Bang>,
+    /// Indices of bangs in `groups` by aliases
+    group_indices: HashMap<String, usize>,
     pub default: String,
+    pub index: AliasIndex,
+    /// Status searches are redirected with, unless their bang overrides it
+    pub redirect_status: StatusCode,
+    /// Mode searches are redirected in, unless their bang overrides it
+    pub redirect_mode: RedirectMode,
 }
 
 pub type Context = String;
@@ -25,6 +117,8 @@ pub enum ParseErr {
     Query(Kind),
     Aliases(Kind),
     Alias(Kind),
+    RedirectStatus(Kind),
+    RedirectMode(Kind),
 }
 
 impl fmt::Display for ParseErr {
@@ -59,14 +153,111 @@ impl fmt::Display for ParseErr {
                 Kind::WrongType(ctx) => format!("Alias is not a string: {}", ctx),
                 _ => format!("Impossible message: {:?}", self),
             },
+            ParseErr::RedirectStatus(kind) => match kind {
+                Kind::Missing(_) => format!("Impossible message: {:?}", self),
+                Kind::WrongType(ctx) => format!("`redirect_status` is not an integer: {}", ctx),
+                Kind::InvalidValue(ctx) => format!(
+                    "`redirect_status` is not one of 301, 302, 303, 307, 308: {}",
+                    ctx
+                ),
+            },
+            ParseErr::RedirectMode(kind) => match kind {
+                Kind::Missing(_) => format!("Impossible message: {:?}", self),
+                Kind::WrongType(ctx) => format!("`redirect_mode` is not a string: {}", ctx),
+                Kind::InvalidValue(ctx) => format!(
+                    "`redirect_mode` is not one of location, no_referrer, refresh: {}",
+                    ctx
+                ),
+            },
         };
         write!(f, "{}", error)
     }
 }
 
 impl BangStorage {
+    pub fn new(groups: Vec<Bang>, default: String) -> Self {
+        let mut bangs = HashMap::new();
+        let mut group_indices = HashMap::new();
+        // Later bangs take over duplicate aliases
+        for (group_index, group) in groups.iter().enumerate() {
+            for alias in &group.aliases {
+                bangs.insert(alias.clone(), group.query.clone());
+                group_indices.insert(alias.clone(), group_index);
+            }
+        }
+        let index = AliasIndex::new(bangs.keys());
+        BangStorage {
+            bangs,
+            groups,
+            group_indices,
+            default,
+            index,
+            redirect_status: StatusCode::SeeOther,
+            redirect_mode: RedirectMode::Location,
+        }
+    }
+
+    /// Finds the status to redirect a search with `alias` with
+    pub fn redirect_status(&self, alias: &str) -> StatusCode {
+        self.group(alias)
+            .and_then(|group| group.redirect_status)
+            .unwrap_or(self.redirect_status)
+    }
+
+    /// Finds the mode to redirect a search with `alias` in
+    pub fn redirect_mode(&self, alias: &str) -> RedirectMode {
+        self.group(alias)
+            .and_then(|group| group.redirect_mode)
+            .unwrap_or(self.redirect_mode)
+    }
+
+    /// Finds the index of the bang that has `alias` in `groups`
+    pub fn group_index(&self, alias: &str) -> Option<usize> {
+        self.group_indices.get(alias).copied()
+    }
+
+    /// Finds the bang that has `alias`
+    pub fn group(&self, alias: &str) -> Option<&Bang> {
+        self.group_index(alias)
+            .map(|group_index| &self.groups[group_index])
+    }
+
+    /// Parses an optional redirect status, `table` is the config or a bang table
+    fn parse_redirect_status(table: &Table) -> Result<Option<StatusCode>, ParseErr> {
+        let value = match table.get("redirect_status") {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let code = value
+            .as_integer()
+            .ok_or(ParseErr::RedirectStatus(Kind::WrongType(value.to_string())))?;
+        REDIRECT_STATUSES
+            .into_iter()
+            .find(|status| i64::from(status.code()) == code)
+            .map(Some)
+            .ok_or(ParseErr::RedirectStatus(Kind::InvalidValue(
+                code.to_string(),
+            )))
+    }
+
+    /// Parses an optional redirect mode, `table` is the config or a bang table
+    fn parse_redirect_mode(table: &Table) -> Result<Option<RedirectMode>, ParseErr> {
+        let value = match table.get("redirect_mode") {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let name = value
+            .as_str()
+            .ok_or(ParseErr::RedirectMode(Kind::WrongType(value.to_string())))?;
+        RedirectMode::NAMES
+            .into_iter()
+            .find(|(mode_name, _)| *mode_name == name)
+            .map(|(_, mode)| Some(mode))
+            .ok_or(ParseErr::RedirectMode(Kind::InvalidValue(name.to_string())))
+    }
+
     pub fn from_table(table: &Table) -> Result<BangStorage, ParseErr> {
-        let mut alias_map = HashMap::new();
+        let mut groups = Vec::new();
 
         let bang_entries = table
             .get("bangs")
@@ -95,24 +286,32 @@ impl BangStorage {
                     bang_table["aliases"].to_string(),
                 )))?;
 
-            if bang_table.len() != 2 {
-                let extra_items: Vec<String> = bang_table
-                    .keys()
-                    .filter(|key: &&String| *key != "aliases" || *key != "query")
-                    .cloned()
-                    .collect();
+            const KEYS: [&str; 4] = ["aliases", "query", "redirect_status", "redirect_mode"];
+            let extra_items: Vec<String> = bang_table
+                .keys()
+                .filter(|key| !KEYS.contains(&key.as_str()))
+                .cloned()
+                .collect();
+            if !extra_items.is_empty() {
                 return Result::Err(ParseErr::Bang(Kind::InvalidValue(format!(
                     "{:?}",
                     extra_items
                 ))));
             }
 
+            let mut alias_strs = Vec::new();
             for alias_entry in aliases {
                 let alias_str = alias_entry
                     .as_str()
                     .ok_or(ParseErr::Alias(Kind::WrongType(alias_entry.to_string())))?;
-                alias_map.insert(alias_str.to_string(), query.to_string());
+                alias_strs.push(alias_str.to_string());
             }
+            groups.push(Bang {
+                aliases: alias_strs,
+                query: query.to_string(),
+                redirect_status: Self::parse_redirect_status(bang_table)?,
+                redirect_mode: Self::parse_redirect_mode(bang_table)?,
+            });
         }
 
         let default = table
@@ -124,14 +323,18 @@ impl BangStorage {
             )))?
             .to_string();
 
-        if !alias_map.contains_key(&default) {
-            return Result::Err(ParseErr::DefaultBang(Kind::InvalidValue(default)));
+        let mut storage = BangStorage::new(groups, default);
+        if let Some(status) = Self::parse_redirect_status(table)? {
+            storage.redirect_status = status;
+        }
+        if let Some(mode) = Self::parse_redirect_mode(table)? {
+            storage.redirect_mode = mode;
+        }
+        if !storage.bangs.contains_key(&storage.default) {
+            return Result::Err(ParseErr::DefaultBang(Kind::InvalidValue(storage.default)));
         }
 
-        Ok(BangStorage {
-            bangs: alias_map,
-            default: default,
-        })
+        Ok(storage)
     }
 }
 
@@ -179,6 +382,74 @@ mod tests {
                 ),
             ])
         );
+        assert_eq!(
+            storage.groups,
+            vec![
+                Bang {
+                    aliases: vec!["duckduckgo".to_string(), "ddg".to_string()],
+                    query: "https://duckduckgo.com/?q={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+                Bang {
+                    aliases: vec!["вікі".to_string(), "в".to_string(), "ukwiki".to_string()],
+                    query: "https://uk.wikipedia.org/w/?search={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+            ]
+        );
+        assert_eq!(storage.groups.get(1), storage.group("в"));
+        assert_eq!(None, storage.group("missing"));
+    }
+
+    #[test]
+    fn duplicate_aliases() {
+        let bang = |aliases: &[&str]| Bang {
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+            query: format!("{}?q={{}}", aliases[0]),
+            redirect_status: None,
+            redirect_mode: None,
+        };
+        let storage = BangStorage::new(
+            vec![bang(&["w", "wiki"]), bang(&["w", "wp"])],
+            "w".to_string(),
+        );
+        // Later bangs take over duplicate aliases
+        assert_eq!(Some(1), storage.group_index("w"));
+        assert_eq!(Some(0), storage.group_index("wiki"));
+        assert_eq!(None, storage.group_index("missing"));
+        assert_eq!(storage.groups.get(1), storage.group("w"));
+        assert_eq!("w?q={}", storage.bangs["w"]);
+    }
+
+    mod index {
+        use super::*;
+
+        fn index() -> AliasIndex {
+            let aliases = ["wikipedia", "wiki", "w", "wiktionary", "ddg"].map(str::to_string);
+            AliasIndex::new(aliases.iter())
+        }
+
+        #[test]
+        fn complete_by_length() {
+            assert_eq!(
+                vec!["w", "wiki", "wikipedia", "wiktionary"],
+                index().complete("w", 10)
+            );
+            assert_eq!(vec!["wiki", "wikipedia"], index().complete("wiki", 10));
+            assert_eq!(Vec::<String>::new(), index().complete("x", 10));
+        }
+
+        #[test]
+        fn complete_by_usage() {
+            let index = index();
+            index.record_use("wiktionary");
+            index.record_use("wiktionary");
+            index.record_use("wikipedia");
+            index.record_use("unknown");
+            assert_eq!(vec!["wiktionary", "wikipedia", "w"], index.complete("w", 3));
+        }
     }
 
     mod default {
@@ -268,7 +539,11 @@ mod tests {
                 extra = 123";
             let table: Table = CONTENT.parse().unwrap();
             let error = BangStorage::from_table(&table).unwrap_err();
-            assert!(matches!(error, ParseErr::Bang(Kind::InvalidValue(_))));
+            // Only unknown keys are reported
+            assert!(
+                matches!(error, ParseErr::Bang(Kind::InvalidValue(ref ctx)) if ctx == r#"["extra"]"#),
+                "{error:?}"
+            );
         }
     }
 
@@ -341,4 +616,108 @@ mod tests {
             assert!(matches!(error, ParseErr::Alias(Kind::WrongType(_))));
         }
     }
+
+    mod redirect_status {
+        use super::*;
+
+        #[test]
+        fn success() {
+            const CONTENT: &str = "
+                default = 'ddg'
+                redirect_status = 302
+                [[bangs]]
+                query = 'https://duckduckgo.com/?q={}'
+                aliases = ['ddg']
+                [[bangs]]
+                query = 'https://en.wikipedia.org/w/?search={}'
+                aliases = ['w', 'wiki']
+                redirect_status = 307";
+            let table: Table = CONTENT.parse().unwrap();
+            let storage = BangStorage::from_table(&table).unwrap();
+            assert_eq!(StatusCode::Found, storage.redirect_status("ddg"));
+            assert_eq!(
+                StatusCode::TemporaryRedirect,
+                storage.redirect_status("wiki")
+            );
+        }
+
+        #[test]
+        fn default() {
+            const CONTENT: &str = "
+                default = 'ddg'
+                [[bangs]]
+                query = 'https://duckduckgo.com/?q={}'
+                aliases = ['ddg']";
+            let table: Table = CONTENT.parse().unwrap();
+            let storage = BangStorage::from_table(&table).unwrap();
+            assert_eq!(StatusCode::SeeOther, storage.redirect_status("ddg"));
+        }
+
+        #[test]
+        fn invalid() {
+            for (status, global) in [("200", true), ("304", false), ("'302'", true)] {
+                let (global_status, bang_status) = match global {
+                    true => (format!("redirect_status = {status}"), String::new()),
+                    false => (String::new(), format!("redirect_status = {status}")),
+                };
+                let content = format!(
+                    "default = 'ddg'\n{global_status}\n[[bangs]]\nquery = 'q'\naliases = ['ddg']\n{bang_status}"
+                );
+                let table: Table = content.parse().unwrap();
+                let error = BangStorage::from_table(&table).unwrap_err();
+                assert!(matches!(error, ParseErr::RedirectStatus(_)), "{content}");
+            }
+        }
+    }
+
+    mod redirect_mode {
+        use super::*;
+
+        #[test]
+        fn success() {
+            const CONTENT: &str = "
+                default = 'ddg'
+                redirect_mode = 'no_referrer'
+                [[bangs]]
+                query = 'https://duckduckgo.com/?q={}'
+                aliases = ['ddg']
+                [[bangs]]
+                query = 'https://en.wikipedia.org/w/?search={}'
+                aliases = ['w', 'wiki']
+                redirect_mode = 'refresh'";
+            let table: Table = CONTENT.parse().unwrap();
+            let storage = BangStorage::from_table(&table).unwrap();
+            assert_eq!(RedirectMode::NoReferrer, storage.redirect_mode("ddg"));
+            assert_eq!(RedirectMode::Refresh, storage.redirect_mode("wiki"));
+            assert_eq!(RedirectMode::NoReferrer, storage.redirect_mode("missing"));
+        }
+
+        #[test]
+        fn default() {
+            const CONTENT: &str = "
+                default = 'ddg'
+                [[bangs]]
+                query = 'https://duckduckgo.com/?q={}'
+                aliases = ['ddg']";
+            let table: Table = CONTENT.parse().unwrap();
+            let storage = BangStorage::from_table(&table).unwrap();
+            assert_eq!(RedirectMode::Location, storage.redirect_mode("ddg"));
+        }
+
+        #[test]
+        fn invalid() {
+            for (mode, global) in [("'html'", true), ("1", false), ("'Refresh'", false)] {
+                let (global_mode, bang_mode) = match global {
+                    true => (format!("redirect_mode = {mode}"), String::new()),
+                    false => (String::new(), format!("redirect_mode = {mode}")),
+                };
+                let content = format!(
+                    "default = 'ddg'\n{global_mode}\n[[bangs]]\nquery = 'q'\naliases = ['ddg']\n{bang_mode}"
+                );
+                let table: Table = content.parse().unwrap();
+                let error = BangStorage::from_table(&table).unwrap_err();
+                assert!(matches!(error, ParseErr::RedirectMode(_)), "{content}");
+            }
+        }
+    }
 }
@@ -53,7 +53,7 @@ impl XdgLookup {
     }
 
     fn parse_absolute(string: Option<&str>) -> Option<PathBuf> {
-        match string.map(|value| PathBuf::from(value)) {
+        match string.map(PathBuf::from) {
             Some(path) if path.is_absolute() => Some(path),
             _ => None,
         }
@@ -136,10 +136,10 @@ impl ConfigLookup {
         binary_path: PathBuf,
     ) -> Self {
         Self {
-            cli_config: cli_config,
+            cli_config,
             env_config: env_var("BANGER_CONFIG").as_deref().map(PathBuf::from),
             xdg: XdgLookup::new(&env_var),
-            binary_path: binary_path,
+            binary_path,
         }
     }
 
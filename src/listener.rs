@@ -0,0 +1,353 @@
+use std::fmt;
+use std::fs::{self, Permissions};
+use std::io::{self, ErrorKind, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::activation::socket_family;
+use crate::log::info;
+use crate::reader::TimedRead;
+#[cfg(feature = "tls")]
+use crate::tls::TlsListener;
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// Address to listen on: `<IP address>:<port>` or `unix:<path>`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(format!("Missing socket path in {value}")),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => value
+                .parse::<SocketAddr>()
+                .map(ListenAddr::Tcp)
+                .map_err(|_err| format!("Failed to parse {value} into socket address")),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(address) => write!(f, "{address}"),
+            ListenAddr::Unix(path) => write!(f, "{UNIX_PREFIX}{}", path.display()),
+        }
+    }
+}
+
+/// Accepted connection, served the same way whatever the listener is
+pub trait Connection: TimedRead + Write + AsRawFd + Send + 'static {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+    fn shutdown(&mut self) -> io::Result<()>;
+    /// Address of the client, Unix sockets have none
+    fn peer_ip(&self) -> Option<IpAddr>;
+
+    /// Checks whether data can be read without waiting for the socket
+    fn has_pending(&mut self) -> bool {
+        false
+    }
+
+    /// Checks whether a write has to wait for the client to finish a handshake first
+    fn is_handshaking(&self) -> bool {
+        false
+    }
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.peer_addr().ok().map(|address| address.ip())
+    }
+}
+
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        None
+    }
+}
+
+/// Connection of any kind, so connections from all listeners share workers
+pub type AnyConnection = Box<dyn Connection>;
+
+impl TimedRead for AnyConnection {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+impl AsRawFd for AnyConnection {
+    fn as_raw_fd(&self) -> RawFd {
+        (**self).as_raw_fd()
+    }
+}
+
+impl Connection for AnyConnection {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        (**self).set_nonblocking(nonblocking)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        (**self).set_write_timeout(timeout)
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        (**self).shutdown()
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        (**self).peer_ip()
+    }
+
+    fn has_pending(&mut self) -> bool {
+        (**self).has_pending()
+    }
+
+    fn is_handshaking(&self) -> bool {
+        (**self).is_handshaking()
+    }
+}
+
+/// Socket that accepts connections
+pub trait Listener: AsRawFd {
+    type Stream: Connection;
+
+    fn accept(&self) -> io::Result<Self::Stream>;
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Listener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept(&self) -> io::Result<TcpStream> {
+        TcpListener::accept(self).map(|(stream, _peer)| stream)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpListener::set_nonblocking(self, nonblocking)
+    }
+}
+
+impl Listener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept(&self) -> io::Result<UnixStream> {
+        UnixListener::accept(self).map(|(stream, _peer)| stream)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixListener::set_nonblocking(self, nonblocking)
+    }
+}
+
+/// Removes a socket file left by a previous run.
+/// Sockets that still accept connections belong to a running server and are kept.
+fn remove_stale_socket(path: &Path) -> Result<(), String> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(format!("{}: {err}", path.display())),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(format!(
+            "{}: file exists and is not a socket",
+            path.display()
+        ));
+    }
+    match UnixStream::connect(path) {
+        Ok(_stream) => Err(format!("{}: socket is in use", path.display())),
+        Err(err) if err.kind() == ErrorKind::ConnectionRefused => {
+            info!("Removing stale socket", path = path.display());
+            fs::remove_file(path).map_err(|err| format!("{}: {err}", path.display()))
+        }
+        Err(err) => Err(format!("{}: {err}", path.display())),
+    }
+}
+
+/// Binds a Unix socket, replacing a stale one, and sets its permissions if `mode` is set
+pub fn bind_unix(path: &Path, mode: Option<u32>) -> Result<UnixListener, String> {
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, Permissions::from_mode(mode))
+            .map_err(|err| format!("Failed to set permissions of {}: {err}", path.display()))?;
+    }
+    Ok(listener)
+}
+
+/// Listening socket, bound by the server or inherited from systemd
+pub enum Bound {
+    Tcp(TcpListener),
+    /// Path is set if the server bound the socket and has to remove it on shutdown
+    Unix(UnixListener, Option<PathBuf>),
+    #[cfg(feature = "tls")]
+    Tls(TlsListener),
+}
+
+impl Bound {
+    pub fn bind(address: &ListenAddr, mode: Option<u32>) -> Result<Bound, String> {
+        match address {
+            ListenAddr::Tcp(address) => TcpListener::bind(address)
+                .map(Bound::Tcp)
+                .map_err(|err| format!("{address}: {err}")),
+            ListenAddr::Unix(path) => {
+                bind_unix(path, mode).map(|listener| Bound::Unix(listener, Some(path.clone())))
+            }
+        }
+    }
+
+    /// Wraps an inherited socket, its kind is taken from its address family
+    pub fn from_fd(fd: OwnedFd) -> Result<Bound, String> {
+        let raw_fd = fd.as_raw_fd();
+        match socket_family(raw_fd) {
+            Ok(libc::AF_INET) | Ok(libc::AF_INET6) => Ok(Bound::Tcp(TcpListener::from(fd))),
+            Ok(libc::AF_UNIX) => Ok(Bound::Unix(UnixListener::from(fd), None)),
+            Ok(family) => Err(format!(
+                "Inherited descriptor {raw_fd} has unsupported address family {family}"
+            )),
+            Err(err) => Err(format!(
+                "Inherited descriptor {raw_fd} is not a socket: {err}"
+            )),
+        }
+    }
+
+    /// Describes the address for logs
+    pub fn describe(&self) -> String {
+        match self {
+            Bound::Tcp(listener) => match listener.local_addr() {
+                Ok(address) => address.to_string(),
+                Err(_err) => "TCP socket".to_string(),
+            },
+            Bound::Unix(listener, _) => match listener.local_addr() {
+                Ok(address) => match address.as_pathname() {
+                    Some(path) => format!("{UNIX_PREFIX}{}", path.display()),
+                    None => "Unix socket".to_string(),
+                },
+                Err(_err) => "Unix socket".to_string(),
+            },
+            #[cfg(feature = "tls")]
+            Bound::Tls(listener) => match listener.local_addr() {
+                Ok(address) => format!("{address} (TLS)"),
+                Err(_err) => "TLS socket".to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    fn socket_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("banger-{}-{name}.sock", process::id()))
+    }
+
+    #[test]
+    fn parse_address() {
+        assert_eq!(
+            Ok(ListenAddr::Tcp("127.0.0.1:8080".parse().unwrap())),
+            "127.0.0.1:8080".parse()
+        );
+        assert_eq!(
+            Ok(ListenAddr::Unix(PathBuf::from("/run/banger/banger.sock"))),
+            "unix:/run/banger/banger.sock".parse()
+        );
+        for value in ["unix:", "127.0.0.1", "localhost:8080"] {
+            assert!(value.parse::<ListenAddr>().is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn display_address() {
+        for value in ["127.0.0.1:8080", "[::1]:80", "unix:/run/banger.sock"] {
+            assert_eq!(value, value.parse::<ListenAddr>().unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn bind_unix_mode() {
+        let path = socket_path("mode");
+        let _listener = bind_unix(&path, Some(0o600)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o600, mode & 0o777);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bind_unix_stale() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path).unwrap());
+        let listener = bind_unix(&path, None).unwrap();
+
+        // Socket of a running server is kept
+        assert!(bind_unix(&path, None).is_err());
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn from_fd() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let bound = Bound::from_fd(OwnedFd::from(listener)).unwrap();
+        assert!(matches!(bound, Bound::Tcp(_)));
+        assert_eq!(address.to_string(), bound.describe());
+
+        let path = socket_path("fd");
+        let listener = UnixListener::bind(&path).unwrap();
+        let bound = Bound::from_fd(OwnedFd::from(listener)).unwrap();
+        assert!(matches!(bound, Bound::Unix(_, None)));
+        assert_eq!(format!("unix:{}", path.display()), bound.describe());
+        fs::remove_file(&path).unwrap();
+
+        let file = fs::File::open("/dev/null").unwrap();
+        assert!(Bound::from_fd(OwnedFd::from(file)).is_err());
+    }
+
+    #[test]
+    fn bind_unix_not_socket() {
+        let path = socket_path("file");
+        fs::write(&path, "").unwrap();
+        assert!(bind_unix(&path, None).is_err());
+        assert!(path.exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
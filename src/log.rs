@@ -0,0 +1,182 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::SystemTime;
+
+use crate::time::rfc3339;
+
+/// Environment variable that sets the log level
+pub const LOG_ENV: &str = "BANGER_LOG";
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value.to_ascii_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!(
+                "Unknown log level, expected one of error, warn, info, debug, trace: {value}"
+            )),
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        };
+        f.write_str(name)
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+/// Sets the most detailed level that is logged
+pub fn set_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Quotes a logfmt value if it has spaces, quotes, `=` or control characters
+fn quote(value: &str) -> String {
+    let plain = !value.is_empty()
+        && !value
+            .chars()
+            .any(|ch| ch == ' ' || ch == '"' || ch == '=' || ch == '\\' || ch.is_control());
+    if plain {
+        return value.to_string();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(ch);
+            }
+            '\n' => quoted.push_str("\\n"),
+            ch if ch.is_control() => quoted.push_str(&format!("\\x{:02x}", ch as u32)),
+            _ => quoted.push(ch),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Formats an event as a logfmt line, e.g. `time=... level=warn msg="..." error="..."`
+fn format_event(
+    time: SystemTime,
+    level: Level,
+    message: &str,
+    fields: &[(&str, &dyn Display)],
+) -> String {
+    let mut line = format!(
+        "time={} level={level} msg={}",
+        rfc3339(time),
+        quote(message)
+    );
+    for (key, value) in fields {
+        line.push_str(&format!(" {key}={}", quote(&value.to_string())));
+    }
+    line
+}
+
+pub fn write(level: Level, message: &str, fields: &[(&str, &dyn Display)]) {
+    eprintln!(
+        "{}",
+        format_event(SystemTime::now(), level, message, fields)
+    );
+}
+
+/// Logs an event with fields if its level is enabled, e.g.
+/// `event!(Level::Warn, "Failed to accept connection", error = err)`
+macro_rules! event {
+    ($level:expr, $message:literal $(, $key:ident = $value:expr)* $(,)?) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write(
+                $level,
+                $message,
+                &[$((stringify!($key), &$value as &dyn ::std::fmt::Display)),*],
+            );
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::log::event!($crate::log::Level::Error, $($arg)*) };
+}
+
+// Named apart from the built-in `warn` attribute, which makes a plain re-export ambiguous
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::log::event!($crate::log::Level::Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { $crate::log::event!($crate::log::Level::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log::event!($crate::log::Level::Debug, $($arg)*) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::log::event!($crate::log::Level::Trace, $($arg)*) };
+}
+
+pub(crate) use {debug, error, event, info, trace, warning as warn};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn levels() {
+        assert_eq!(Ok(Level::Warn), "WARN".parse());
+        assert_eq!(Ok(Level::Trace), "trace".parse());
+        assert!("verbose".parse::<Level>().is_err());
+        assert!(Level::Error < Level::Debug);
+        assert_eq!("debug", Level::Debug.to_string());
+    }
+
+    #[test]
+    fn format() {
+        let time = UNIX_EPOCH + Duration::from_secs(971186136);
+        let error = "Broken pipe (os error 32)";
+        assert_eq!(
+            r#"time=2000-10-10T13:55:36Z level=warn msg="Failed to write" peer=127.0.0.1 error="Broken pipe (os error 32)" empty="" quoted="a\"b=c""#,
+            format_event(
+                time,
+                Level::Warn,
+                "Failed to write",
+                &[
+                    ("peer", &"127.0.0.1"),
+                    ("error", &error),
+                    ("empty", &""),
+                    ("quoted", &"a\"b=c"),
+                ]
+            )
+        );
+    }
+}
@@ -1,9 +1,13 @@
+use std::env;
 use std::fs;
-use std::net::SocketAddr;
 use std::path::PathBuf;
 
 use clap::Parser;
-use toml::Table;
+use toml::{Table, Value};
+
+mod access_log;
+
+mod activation;
 
 mod config_lookup;
 use crate::config_lookup::ConfigLookup;
@@ -11,6 +15,22 @@ use crate::config_lookup::ConfigLookup;
 mod bang_storage;
 use crate::bang_storage::BangStorage;
 
+mod listener;
+use crate::listener::{Bound, ListenAddr};
+
+mod log;
+use crate::log::{LOG_ENV, Level, info};
+
+mod metrics;
+
+mod reader;
+
+mod pages;
+
+mod pool;
+
+mod rate_limit;
+
 mod request;
 
 mod server;
@@ -18,49 +38,186 @@ use crate::server::serve;
 
 mod response;
 
+mod settings;
+use crate::settings::Settings;
+
+mod signal;
+
+mod time;
+
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+use crate::tls::{TlsListener, TlsSettings};
+
 #[derive(Parser, Debug)]
 struct Args {
     /// Path of config file for banger
     #[arg(short, long)]
     config: Option<PathBuf>,
-    /// Address and port to bind to in <IP address>:<port> format
+    /// Address to bind to in <IP address>:<port> or unix:<path> format, may be repeated
+    #[arg(short, long)]
+    address: Vec<ListenAddr>,
+    /// Number of threads serving connections
+    #[arg(short, long, value_parser = clap::value_parser!(u64).range(1..))]
+    workers: Option<u64>,
+    /// Number of connections that may wait for a free worker
+    #[arg(short, long)]
+    queue_depth: Option<usize>,
+    /// Most detailed log level: error, warn, info, debug or trace
     #[arg(short, long)]
-    address: Option<SocketAddr>,
+    log_level: Option<Level>,
 }
 
-fn get_address_from_config(table: &Table) -> Result<SocketAddr, String> {
-    let value = table
-        .get("address")
-        .ok_or("Address is missing from config".to_string())?;
+fn parse_address(value: &Value) -> Result<ListenAddr, String> {
     let addr_str = value
         .as_str()
         .ok_or(format!("Address is not a string: {:?}", value))?;
-    let addr = addr_str
-        .parse::<SocketAddr>()
-        .map_err(|_err| format!("Failed to parse {addr_str} into socket address"))?;
-    Ok(addr)
+    addr_str.parse::<ListenAddr>()
+}
+
+fn get_addresses_from_config(table: &Table) -> Result<Vec<ListenAddr>, String> {
+    let value = match table.get("address") {
+        Some(value) => value,
+        None => return Ok(Vec::new()),
+    };
+    match value {
+        Value::Array(values) if values.is_empty() => Err("Address list is empty".to_string()),
+        Value::Array(values) => values.iter().map(parse_address).collect(),
+        value => parse_address(value).map(|addr| vec![addr]),
+    }
+}
+
+/// Binds HTTPS listeners from the `tls` table
+#[cfg(feature = "tls")]
+fn bind_tls(table: &Table) -> Result<Vec<Bound>, String> {
+    let tls = match TlsSettings::from_table(table)? {
+        Some(tls) => tls,
+        None => return Ok(Vec::new()),
+    };
+    let config = tls::server_config(&tls)?;
+    tls.addresses
+        .into_iter()
+        .map(|address| TlsListener::bind(address, config.clone()).map(Bound::Tls))
+        .collect()
+}
+
+#[cfg(not(feature = "tls"))]
+fn bind_tls(table: &Table) -> Result<Vec<Bound>, String> {
+    if table.contains_key("tls") {
+        return Err(
+            "`tls` is set, but TLS support is not built, rebuild with `--features tls`".to_string(),
+        );
+    }
+    Ok(Vec::new())
 }
 
 fn main() -> Result<(), String> {
     // Read CLI arguments
     let args = Args::parse();
 
+    // Set log level: CLI takes precedence over environment, environment over config
+    let log_level = match args.log_level {
+        Some(level) => Some(level),
+        None => match env::var(LOG_ENV) {
+            Ok(value) => Some(
+                value
+                    .parse::<Level>()
+                    .map_err(|err| format!("`{LOG_ENV}` is invalid: {err}"))?,
+            ),
+            Err(_err) => None,
+        },
+    };
+    if let Some(level) = log_level {
+        log::set_level(level);
+    }
+
     // Parse config
     let config_path = ConfigLookup::new(args.config)
         .lookup()
         .ok_or("Failed to find config".to_string())?;
-    eprintln!("Reading config from {}", config_path.display());
+    info!("Reading config", path = config_path.display());
 
     let content = fs::read_to_string(&config_path)
         .map_err(|err| format!("{}: {}", config_path.display(), err))?;
     let table = content.parse::<Table>().map_err(|err| format!("{err}"))?;
     let storage = BangStorage::from_table(&table).map_err(|err| format!("{err}"))?;
+    let mut settings = Settings::from_table(&table)?;
+    if log_level.is_none() {
+        log::set_level(settings.log_level);
+    }
+    if let Some(workers) = args.workers {
+        settings.workers = workers as usize;
+    }
+    if let Some(queue_depth) = args.queue_depth {
+        settings.queue_depth = queue_depth;
+    }
 
-    // Serve
-    let listen_address = match args.address {
-        Some(addr) => addr,
-        None => get_address_from_config(&table)?,
+    // Serve, sockets passed by systemd take precedence over addresses
+    let inherited = activation::listen_fds()?;
+    let mut listeners = if inherited.is_empty() {
+        let listen_addresses = if args.address.is_empty() {
+            get_addresses_from_config(&table)?
+        } else {
+            args.address
+        };
+        listen_addresses
+            .iter()
+            .map(|addr| Bound::bind(addr, settings.socket_mode))
+            .collect::<Result<Vec<_>, _>>()?
+    } else {
+        inherited
+            .into_iter()
+            .map(Bound::from_fd)
+            .collect::<Result<Vec<_>, _>>()?
     };
-    eprintln!("Listening on {listen_address}");
-    serve(storage, listen_address)
+    listeners.extend(bind_tls(&table)?);
+    if listeners.is_empty() {
+        return Err("Address is missing from config".to_string());
+    }
+    for listener in &listeners {
+        info!("Listening", address = listener.describe());
+    }
+    let admin = match &settings.metrics_address {
+        Some(address) => {
+            let listener = Bound::bind(address, settings.socket_mode)?;
+            info!("Listening for metrics", address = listener.describe());
+            Some(listener)
+        }
+        None => None,
+    };
+    serve(storage, settings, listeners, admin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn addresses_from_config() {
+        const CONTENTS: [(&str, usize); 3] = [
+            ("address = '127.0.0.1:8080'", 1),
+            ("address = ['127.0.0.1:8080', '[::1]:8080']", 2),
+            ("address = ['127.0.0.1:8080', 'unix:/run/banger.sock']", 2),
+        ];
+        for (content, count) in CONTENTS {
+            let table: Table = content.parse().unwrap();
+            let addresses = get_addresses_from_config(&table).unwrap();
+            assert_eq!(count, addresses.len(), "{content}");
+        }
+        assert!(get_addresses_from_config(&Table::new()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_addresses() {
+        for content in [
+            "address = []",
+            "address = 8080",
+            "address = ['127.0.0.1:8080', 8080]",
+            "address = ['localhost:8080']",
+        ] {
+            let table: Table = content.parse().unwrap();
+            assert!(get_addresses_from_config(&table).is_err(), "{content}");
+        }
+    }
 }
@@ -0,0 +1,359 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::bang_storage::BangStorage;
+use crate::request::QueryErr;
+
+pub const METRICS_PATH: &str = "metrics";
+pub const METRICS_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds of latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Label values of `QueryErr` variants
+const PARSE_ERRORS: [&str; 7] = [
+    "bad_request",
+    "method_not_allowed",
+    "malformed_header",
+    "header_too_large",
+    "uri_too_long",
+    "content_too_large",
+    "unsupported_media_type",
+];
+
+fn parse_error_index(err: &QueryErr) -> usize {
+    match err {
+        QueryErr::BadRequest(_) => 0,
+        QueryErr::MethodNotAllowed => 1,
+        QueryErr::MalformedHeader(_) => 2,
+        QueryErr::HeaderTooLarge(_) => 3,
+        QueryErr::UriTooLong(_) => 4,
+        QueryErr::ContentTooLarge(_) => 5,
+        QueryErr::UnsupportedMediaType(_) => 6,
+    }
+}
+
+/// Escapes a label value of the Prometheus text format
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(index) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[index] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, label: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.buckets) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{{label},le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{{label},le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum{{{label}}} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{{{label}}} {}", self.count);
+    }
+}
+
+/// Counters of served requests, rendered in Prometheus text format
+pub struct Metrics {
+    /// Responses by status code
+    responses: Mutex<BTreeMap<u16, u64>>,
+    /// Redirects by the index of the bang in `BangStorage::groups`
+    redirects: Vec<AtomicU64>,
+    /// Label of each bang: its first alias that is not taken over by a later bang,
+    /// bangs without one are never redirected to
+    bang_labels: Vec<Option<String>>,
+    default_fallbacks: AtomicU64,
+    unknown_bangs: AtomicU64,
+    parse_errors: [AtomicU64; PARSE_ERRORS.len()],
+    active_connections: AtomicI64,
+    /// Latency by route
+    latency: Mutex<BTreeMap<&'static str, Histogram>>,
+}
+
+impl Metrics {
+    pub fn new(storage: &BangStorage) -> Self {
+        Metrics {
+            responses: Mutex::new(BTreeMap::new()),
+            redirects: storage.groups.iter().map(|_| AtomicU64::new(0)).collect(),
+            bang_labels: storage
+                .groups
+                .iter()
+                .enumerate()
+                .map(|(group_index, bang)| {
+                    bang.aliases
+                        .iter()
+                        .find(|alias| storage.group_index(alias) == Some(group_index))
+                        .cloned()
+                })
+                .collect(),
+            default_fallbacks: AtomicU64::new(0),
+            unknown_bangs: AtomicU64::new(0),
+            parse_errors: Default::default(),
+            active_connections: AtomicI64::new(0),
+            latency: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn record_response(&self, status: u16) {
+        let mut responses = match self.responses.lock() {
+            Ok(responses) => responses,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        *responses.entry(status).or_default() += 1;
+    }
+
+    /// Counts a redirect by the bang that has `alias`
+    pub fn record_redirect(&self, storage: &BangStorage, alias: &str) {
+        if let Some(counter) = storage
+            .group_index(alias)
+            .and_then(|group_index| self.redirects.get(group_index))
+        {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_default_fallback(&self) {
+        self.default_fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_unknown_bang(&self) {
+        self.unknown_bangs.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_parse_error(&self, err: &QueryErr) {
+        self.parse_errors[parse_error_index(err)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_opened(&self) {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn record_latency(&self, route: &'static str, latency: Duration) {
+        let mut histograms = match self.latency.lock() {
+            Ok(histograms) => histograms,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        histograms
+            .entry(route)
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    /// Renders all metrics in Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP banger_requests_total Requests by response status code.\n");
+        out.push_str("# TYPE banger_requests_total counter\n");
+        if let Ok(responses) = self.responses.lock() {
+            for (code, count) in responses.iter() {
+                let _ = writeln!(out, "banger_requests_total{{code=\"{code}\"}} {count}");
+            }
+        }
+
+        out.push_str(
+            "# HELP banger_redirects_total Redirects by bang, named by its first alias.\n",
+        );
+        out.push_str("# TYPE banger_redirects_total counter\n");
+        for (bang, count) in self.bang_labels.iter().zip(&self.redirects) {
+            if let Some(bang) = bang {
+                let _ = writeln!(
+                    out,
+                    "banger_redirects_total{{bang=\"{}\"}} {}",
+                    escape_label(bang),
+                    count.load(Ordering::Relaxed)
+                );
+            }
+        }
+
+        out.push_str(
+            "# HELP banger_default_bang_total Searches without a bang that used the default one.\n",
+        );
+        out.push_str("# TYPE banger_default_bang_total counter\n");
+        let _ = writeln!(
+            out,
+            "banger_default_bang_total {}",
+            self.default_fallbacks.load(Ordering::Relaxed)
+        );
+
+        out.push_str(
+            "# HELP banger_unknown_bangs_total Search terms that look like bangs but are not configured.\n",
+        );
+        out.push_str("# TYPE banger_unknown_bangs_total counter\n");
+        let _ = writeln!(
+            out,
+            "banger_unknown_bangs_total {}",
+            self.unknown_bangs.load(Ordering::Relaxed)
+        );
+
+        out.push_str("# HELP banger_parse_errors_total Rejected requests by error.\n");
+        out.push_str("# TYPE banger_parse_errors_total counter\n");
+        for (kind, count) in PARSE_ERRORS.iter().zip(&self.parse_errors) {
+            let _ = writeln!(
+                out,
+                "banger_parse_errors_total{{kind=\"{kind}\"}} {}",
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        out.push_str("# HELP banger_active_connections Connections being served by workers.\n");
+        out.push_str("# TYPE banger_active_connections gauge\n");
+        let _ = writeln!(
+            out,
+            "banger_active_connections {}",
+            self.active_connections.load(Ordering::Relaxed)
+        );
+
+        out.push_str(
+            "# HELP banger_request_duration_seconds Time from reading a request to sending the response.\n",
+        );
+        out.push_str("# TYPE banger_request_duration_seconds histogram\n");
+        if let Ok(histograms) = self.latency.lock() {
+            for (route, histogram) in histograms.iter() {
+                let label = format!("route=\"{}\"", escape_label(route));
+                histogram.render(&mut out, "banger_request_duration_seconds", &label);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bang_storage::Bang;
+
+    fn storage() -> BangStorage {
+        BangStorage::new(
+            vec![
+                Bang {
+                    aliases: vec!["wikipedia".to_string(), "w".to_string()],
+                    query: "wiki?q={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+                Bang {
+                    aliases: vec!["say \"hi\"".to_string()],
+                    query: "hi?q={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+            ],
+            "w".to_string(),
+        )
+    }
+
+    #[test]
+    fn counters() {
+        let storage = storage();
+        let metrics = Metrics::new(&storage);
+        metrics.record_redirect(&storage, "w");
+        metrics.record_redirect(&storage, "wikipedia");
+        metrics.record_redirect(&storage, "unknown");
+        metrics.record_response(303);
+        metrics.record_response(303);
+        metrics.record_response(404);
+        metrics.record_parse_error(&QueryErr::MethodNotAllowed);
+        metrics.record_default_fallback();
+        metrics.record_unknown_bang();
+        metrics.connection_opened();
+        metrics.connection_opened();
+        metrics.connection_closed();
+
+        let rendered = metrics.render();
+        for line in [
+            "banger_requests_total{code=\"303\"} 2\n",
+            "banger_requests_total{code=\"404\"} 1\n",
+            "banger_redirects_total{bang=\"wikipedia\"} 2\n",
+            "banger_redirects_total{bang=\"say \\\"hi\\\"\"} 0\n",
+            "banger_default_bang_total 1\n",
+            "banger_unknown_bangs_total 1\n",
+            "banger_parse_errors_total{kind=\"method_not_allowed\"} 1\n",
+            "banger_parse_errors_total{kind=\"bad_request\"} 0\n",
+            "banger_active_connections 1\n",
+        ] {
+            assert!(rendered.contains(line), "{line:?} in {rendered}");
+        }
+        assert!(!rendered.contains("bang=\"w\""));
+    }
+
+    #[test]
+    fn shared_first_alias() {
+        let bang = |aliases: &[&str]| Bang {
+            aliases: aliases.iter().map(|alias| alias.to_string()).collect(),
+            query: "q={}".to_string(),
+            redirect_status: None,
+            redirect_mode: None,
+        };
+        let storage = BangStorage::new(
+            vec![
+                bang(&["w", "wiki"]),
+                bang(&["w", "wp"]),
+                bang(&["x"]),
+                bang(&["x"]),
+            ],
+            "w".to_string(),
+        );
+        let metrics = Metrics::new(&storage);
+        metrics.record_redirect(&storage, "w");
+        metrics.record_redirect(&storage, "wp");
+        metrics.record_redirect(&storage, "wiki");
+
+        // Bangs are named by their first alias that is not taken over
+        let rendered = metrics.render();
+        for line in [
+            "banger_redirects_total{bang=\"wiki\"} 1\n",
+            "banger_redirects_total{bang=\"w\"} 2\n",
+            "banger_redirects_total{bang=\"x\"} 0\n",
+        ] {
+            assert!(rendered.contains(line), "{line:?} in {rendered}");
+        }
+        assert_eq!(1, rendered.matches("bang=\"x\"").count(), "{rendered}");
+    }
+
+    #[test]
+    fn histogram() {
+        let metrics = Metrics::new(&storage());
+        metrics.record_latency("search", Duration::from_micros(800));
+        metrics.record_latency("search", Duration::from_millis(20));
+        metrics.record_latency("search", Duration::from_secs(5));
+
+        let rendered = metrics.render();
+        for line in [
+            "banger_request_duration_seconds_bucket{route=\"search\",le=\"0.0005\"} 0\n",
+            "banger_request_duration_seconds_bucket{route=\"search\",le=\"0.001\"} 1\n",
+            "banger_request_duration_seconds_bucket{route=\"search\",le=\"0.025\"} 2\n",
+            "banger_request_duration_seconds_bucket{route=\"search\",le=\"2.5\"} 2\n",
+            "banger_request_duration_seconds_bucket{route=\"search\",le=\"+Inf\"} 3\n",
+            "banger_request_duration_seconds_count{route=\"search\"} 3\n",
+        ] {
+            assert!(rendered.contains(line), "{line:?} in {rendered}");
+        }
+    }
+}
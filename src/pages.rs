@@ -0,0 +1,310 @@
+use crate::bang_storage::BangStorage;
+use crate::request::SEARCH_PATH;
+use crate::response::encode;
+
+/// Escapes text for use in XML and HTML content and attribute values
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+/// Quotes and escapes text as JSON string
+pub fn escape_json(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for ch in text.chars() {
+        match ch {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            ch if ch.is_control() => escaped.push_str(&format!("\\u{:04x}", ch as u32)),
+            _ => escaped.push(ch),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+pub const OPENSEARCH_PATH: &str = "opensearch.xml";
+pub const OPENSEARCH_TYPE: &str = "application/opensearchdescription+xml";
+pub const SUGGEST_PATH: &str = "suggest";
+pub const SUGGEST_TYPE: &str = "application/x-suggestions+json";
+
+/// Makes OpenSearch description document, so browsers can add banger as a search engine
+pub fn opensearch(short_name: &str, base_url: &str, query_param: &str) -> String {
+    let short_name = escape(short_name);
+    let search = escape(&format!(
+        "{base_url}/{SEARCH_PATH}?{}={{searchTerms}}",
+        encode(query_param)
+    ));
+    let suggest = escape(&format!(
+        "{base_url}/{SUGGEST_PATH}?{}={{searchTerms}}",
+        encode(query_param)
+    ));
+    let this = escape(&format!("{base_url}/{OPENSEARCH_PATH}"));
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
+  <ShortName>{short_name}</ShortName>
+  <Description>{short_name}: search with bangs</Description>
+  <InputEncoding>UTF-8</InputEncoding>
+  <Url type="text/html" method="get" template="{search}"/>
+  <Url type="{SUGGEST_TYPE}" method="get" template="{suggest}"/>
+  <Url type="{OPENSEARCH_TYPE}" rel="self" template="{this}"/>
+</OpenSearchDescription>
+"#
+    )
+}
+
+/// Makes OpenSearch Suggestions response: the query and completions of it
+pub fn suggestions(query: &str, completions: &[String]) -> String {
+    let completions: Vec<String> = completions.iter().map(|text| escape_json(text)).collect();
+    format!("[{},[{}]]", escape_json(query), completions.join(","))
+}
+
+pub const BANGS_PATH: &str = "bangs";
+/// Bang that shows the list of bangs instead of searching, unless it is configured
+pub const BANGS_ALIAS: &str = "bangs";
+
+/// Makes `<link>` that lets browsers discover the OpenSearch description
+fn opensearch_link(short_name: &str, base_url: &str) -> String {
+    format!(
+        r#"<link rel="search" type="{OPENSEARCH_TYPE}" title="{}" href="{}">"#,
+        escape(short_name),
+        escape(&format!("{base_url}/{OPENSEARCH_PATH}"))
+    )
+}
+
+/// Page-specific parts of an HTML document
+struct PageContent<'a> {
+    title: &'a str,
+    /// Extra elements of `<head>`, each on its own line, escaped already
+    head: &'a str,
+    /// Escaped already
+    body: &'a str,
+}
+
+/// Makes HTML document that lets browsers discover banger as a search engine
+fn html_page(short_name: &str, base_url: &str, content: PageContent) -> String {
+    let PageContent { title, head, body } = content;
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+{head}<title>{}</title>
+{}
+</head>
+<body>
+{body}
+</body>
+</html>
+"#,
+        escape(title),
+        opensearch_link(short_name, base_url)
+    )
+}
+
+/// Lists bangs with their aliases and queries, marking the default one
+pub fn bangs_html(storage: &BangStorage, short_name: &str, base_url: &str) -> String {
+    let default = storage.group(&storage.default);
+    let mut rows = String::new();
+    for group in &storage.groups {
+        let aliases: Vec<String> = group
+            .aliases
+            .iter()
+            .map(|alias| format!("<code>!{}</code>", escape(alias)))
+            .collect();
+        let mark = match default {
+            Some(default) if std::ptr::eq(default, group) => " <strong>(default)</strong>",
+            _ => "",
+        };
+        rows.push_str(&format!(
+            "<tr><td>{}{mark}</td><td><code>{}</code></td></tr>\n",
+            aliases.join(", "),
+            escape(&group.query)
+        ));
+    }
+    let title = format!("{short_name}: bangs");
+    let body = format!(
+        "<h1>{}</h1>\n\
+        <p>Add a bang anywhere in the query to search with it, e.g. <code>!{} terms</code>. \
+        Queries without bangs use the default bang.</p>\n\
+        <table>\n<tr><th>Bang</th><th>Query</th></tr>\n{rows}</table>",
+        escape(&title),
+        escape(&storage.default)
+    );
+    html_page(
+        short_name,
+        base_url,
+        PageContent {
+            title: &title,
+            head: "",
+            body: &body,
+        },
+    )
+}
+
+/// Lists bangs as JSON object: the default alias and queries by aliases
+pub fn bangs_json(storage: &BangStorage) -> String {
+    let mut aliases: Vec<&String> = storage.bangs.keys().collect();
+    aliases.sort();
+    let bangs: Vec<String> = aliases
+        .into_iter()
+        .map(|alias| {
+            format!(
+                "{}:{}",
+                escape_json(alias),
+                escape_json(&storage.bangs[alias])
+            )
+        })
+        .collect();
+    format!(
+        "{{\"default\":{},\"bangs\":{{{}}}}}",
+        escape_json(&storage.default),
+        bangs.join(",")
+    )
+}
+
+/// Makes HTML document that sends the browser to `url` without `Referer`
+pub fn refresh_html(short_name: &str, base_url: &str, url: &str) -> String {
+    let url = escape(url);
+    let head = format!(
+        "<meta name=\"referrer\" content=\"no-referrer\">\n\
+        <meta http-equiv=\"refresh\" content=\"0; url={url}\">\n"
+    );
+    let body =
+        format!(r#"<p><a href="{url}" rel="noreferrer">Continue to the search results</a></p>"#);
+    html_page(
+        short_name,
+        base_url,
+        PageContent {
+            title: short_name,
+            head: &head,
+            body: &body,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bang_storage::Bang;
+
+    #[test]
+    fn escape_success() {
+        assert_eq!(
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;привіт",
+            escape("<a href=\"x\">&'привіт")
+        );
+    }
+
+    #[test]
+    fn opensearch_success() {
+        let document = opensearch("Bang & go", "https://banger.example", "q");
+        assert!(document.contains("<ShortName>Bang &amp; go</ShortName>"));
+        assert!(document.contains(r#"template="https://banger.example/search?q={searchTerms}""#));
+        assert!(document.contains(r#"template="https://banger.example/opensearch.xml""#));
+        assert!(document.contains(r#"template="https://banger.example/suggest?q={searchTerms}""#));
+    }
+
+    #[test]
+    fn opensearch_link_success() {
+        assert_eq!(
+            r#"<link rel="search" type="application/opensearchdescription+xml" title="&lt;b&gt;" href="https://host/banger/opensearch.xml">"#,
+            opensearch_link("<b>", "https://host/banger")
+        );
+    }
+
+    #[test]
+    fn refresh_html_success() {
+        let document = refresh_html("Banger", "https://banger.example", "https://w/?q=a&b='c'");
+        assert!(document.contains(&opensearch_link("Banger", "https://banger.example")));
+        assert!(document.contains(r#"<meta name="referrer" content="no-referrer">"#));
+        assert!(document.contains(
+            r#"<meta http-equiv="refresh" content="0; url=https://w/?q=a&amp;b=&#39;c&#39;">"#
+        ));
+        assert!(
+            document.contains(r#"<a href="https://w/?q=a&amp;b=&#39;c&#39;" rel="noreferrer">"#)
+        );
+    }
+
+    #[test]
+    fn bangs_html_success() {
+        let storage = BangStorage::new(
+            vec![
+                Bang {
+                    aliases: vec!["w".to_string(), "wiki".to_string()],
+                    query: "https://w/?q={}&x=<y>".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+                Bang {
+                    aliases: vec!["ddg".to_string()],
+                    query: "https://ddg/?q={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+            ],
+            "ddg".to_string(),
+        );
+        let page = bangs_html(&storage, "Banger", "https://banger.example");
+        assert!(page.contains(&opensearch_link("Banger", "https://banger.example")));
+        assert!(page.contains(
+            "<tr><td><code>!w</code>, <code>!wiki</code></td><td><code>https://w/?q={}&amp;x=&lt;y&gt;</code></td></tr>"
+        ));
+        assert!(page.contains(
+            "<tr><td><code>!ddg</code> <strong>(default)</strong></td><td><code>https://ddg/?q={}</code></td></tr>"
+        ));
+    }
+
+    #[test]
+    fn bangs_json_success() {
+        let storage = BangStorage::new(
+            vec![
+                Bang {
+                    aliases: vec!["w".to_string()],
+                    query: "https://w/?q={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+                Bang {
+                    aliases: vec!["ddg".to_string()],
+                    query: "https://ddg/?q={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+            ],
+            "ddg".to_string(),
+        );
+        assert_eq!(
+            r#"{"default":"ddg","bangs":{"ddg":"https://ddg/?q={}","w":"https://w/?q={}"}}"#,
+            bangs_json(&storage)
+        );
+    }
+
+    #[test]
+    fn suggestions_success() {
+        assert_eq!(
+            r#"["a \"!w",["a \"!wiki","a \"!w\\\n\u0000"]]"#,
+            suggestions(
+                "a \"!w",
+                &["a \"!wiki".to_string(), "a \"!w\\\n\0".to_string()]
+            )
+        );
+        assert_eq!(r#"["",[]]"#, suggestions("", &[]));
+    }
+}
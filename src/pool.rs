@@ -0,0 +1,196 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TrySendError, channel, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Fixed set of worker threads that handle items from a bounded queue
+pub struct ThreadPool<T: Send + 'static> {
+    sender: SyncSender<T>,
+    workers: usize,
+    /// Receives a message from every worker that exited, locked so the pool is shared
+    /// by accept loops of several listeners
+    finished: Mutex<Receiver<()>>,
+}
+
+/// Reports worker exit, even if the worker panicked
+struct FinishGuard(Sender<()>);
+
+impl Drop for FinishGuard {
+    fn drop(&mut self) {
+        let _ = self.0.send(());
+    }
+}
+
+fn work<T>(
+    receiver: Arc<Mutex<Receiver<T>>>,
+    handler: Arc<dyn Fn(T) + Send + Sync>,
+    finished: Sender<()>,
+) {
+    let _guard = FinishGuard(finished);
+    loop {
+        // The lock is released before handling, so other workers may take items meanwhile
+        let item = match receiver.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_poisoned) => return,
+        };
+        match item {
+            // The panic hook has logged the panic, the worker stays to take further items
+            Ok(item) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| handler(item)));
+            }
+            Err(_disconnected) => return,
+        }
+    }
+}
+
+impl<T: Send + 'static> ThreadPool<T> {
+    /// Starts `workers` threads; up to `queue_depth` items may wait for a free worker
+    pub fn new(
+        workers: usize,
+        queue_depth: usize,
+        handler: Arc<dyn Fn(T) + Send + Sync>,
+    ) -> Result<Self, String> {
+        let (sender, receiver) = sync_channel(queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let (finished_sender, finished) = channel();
+        for index in 0..workers {
+            let receiver_cl = receiver.clone();
+            let handler_cl = handler.clone();
+            let finished_cl = finished_sender.clone();
+            thread::Builder::new()
+                .name(format!("worker-{index}"))
+                .spawn(move || work(receiver_cl, handler_cl, finished_cl))
+                .map_err(|err| format!("Failed to start worker thread: {err}"))?;
+        }
+        Ok(ThreadPool {
+            sender,
+            workers,
+            finished: Mutex::new(finished),
+        })
+    }
+
+    /// Queues an item for the workers, gives it back if the queue is full
+    pub fn try_execute(&self, item: T) -> Result<(), T> {
+        match self.sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) | Err(TrySendError::Disconnected(item)) => Err(item),
+        }
+    }
+
+    /// Stops taking new items and waits up to `grace` for workers to handle queued ones.
+    /// Returns the number of workers that are still busy.
+    pub fn join(self, grace: Duration) -> usize {
+        drop(self.sender);
+        let deadline = Instant::now() + grace;
+        let mut busy = self.workers;
+        let finished = match self.finished.into_inner() {
+            Ok(finished) => finished,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        while busy > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match finished.recv_timeout(left) {
+                Ok(()) => busy -= 1,
+                Err(_timeout) => break,
+            }
+        }
+        busy
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn executes() {
+        let (done_sender, done_receiver) = channel();
+        let done_sender = Mutex::new(done_sender);
+        let pool = ThreadPool::new(
+            2,
+            4,
+            Arc::new(move |item: u32| done_sender.lock().unwrap().send(item * 2).unwrap()),
+        )
+        .unwrap();
+        for item in 0..4 {
+            pool.try_execute(item).unwrap();
+        }
+        let mut results: Vec<u32> = done_receiver.iter().take(4).collect();
+        results.sort();
+        assert_eq!(vec![0, 2, 4, 6], results);
+    }
+
+    #[test]
+    fn rejects_when_full() {
+        let (release_sender, release_receiver) = channel::<()>();
+        let (started_sender, started_receiver) = channel();
+        let release_receiver = Mutex::new(release_receiver);
+        let started_sender = Mutex::new(started_sender);
+        let pool = ThreadPool::new(
+            1,
+            1,
+            Arc::new(move |_item: u32| {
+                started_sender.lock().unwrap().send(()).unwrap();
+                let _ = release_receiver.lock().unwrap().recv();
+            }),
+        )
+        .unwrap();
+
+        // First item occupies the only worker, second one waits in the queue
+        pool.try_execute(1).unwrap();
+        started_receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        pool.try_execute(2).unwrap();
+        assert_eq!(Err(3), pool.try_execute(3));
+
+        drop(release_sender);
+    }
+
+    #[test]
+    fn survives_panic() {
+        let (done_sender, done_receiver) = channel();
+        let done_sender = Mutex::new(done_sender);
+        let pool = ThreadPool::new(
+            1,
+            2,
+            Arc::new(move |item: u32| {
+                assert_ne!(0, item, "item panics");
+                done_sender.lock().unwrap().send(item).unwrap();
+            }),
+        )
+        .unwrap();
+        pool.try_execute(0).unwrap();
+        pool.try_execute(1).unwrap();
+        assert_eq!(Ok(1), done_receiver.recv_timeout(Duration::from_secs(5)));
+        // The only worker is still there and exits on join
+        assert_eq!(0, pool.join(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn join() {
+        let (release_sender, release_receiver) = channel::<()>();
+        let release_receiver = Mutex::new(release_receiver);
+        let pool = ThreadPool::new(
+            2,
+            2,
+            Arc::new(move |block: bool| {
+                if block {
+                    let _ = release_receiver.lock().unwrap().recv();
+                }
+            }),
+        )
+        .unwrap();
+        pool.try_execute(true).unwrap();
+        pool.try_execute(false).unwrap();
+        assert_eq!(1, pool.join(Duration::from_millis(100)));
+        drop(release_sender);
+    }
+
+    #[test]
+    fn join_idle() {
+        let pool = ThreadPool::new(4, 0, Arc::new(|_item: ()| ())).unwrap();
+        assert_eq!(0, pool.join(Duration::from_secs(5)));
+    }
+}
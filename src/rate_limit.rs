@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use toml::{Table, Value};
+
+/// How often buckets of clients that went quiet are dropped
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+/// Slowest accepted rate, a request a day
+const MIN_RATE: f64 = 1.0 / 86400.0;
+
+/// Token bucket limits shared by every client of the same subnet
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimitSettings {
+    /// Requests per second a client may keep sending
+    pub rate: f64,
+    /// Requests a client may send at once after being quiet
+    pub burst: u32,
+    /// IPv4 clients that share the prefix share a bucket
+    pub ipv4_prefix: u8,
+    /// IPv6 clients that share the prefix share a bucket
+    pub ipv6_prefix: u8,
+}
+
+fn get_prefix(table: &Table, key: &str, default: u8, max: u8) -> Result<u8, String> {
+    match table.get(key) {
+        None => Ok(default),
+        Some(Value::Integer(value)) => match u8::try_from(*value) {
+            Ok(prefix) if prefix <= max => Ok(prefix),
+            _ => Err(format!("`rate_limit.{key}` must be at most {max}: {value}")),
+        },
+        Some(value) => Err(format!("`rate_limit.{key}` is not an integer: {value}")),
+    }
+}
+
+impl RateLimitSettings {
+    pub fn from_table(table: &Table) -> Result<Option<RateLimitSettings>, String> {
+        let limit = match table.get("rate_limit") {
+            None => return Ok(None),
+            Some(Value::Table(limit)) => limit,
+            Some(value) => return Err(format!("`rate_limit` is not a table: {value}")),
+        };
+        let rate = match limit.get("rate") {
+            None => return Err("`rate_limit.rate` is missing".to_string()),
+            Some(Value::Integer(value)) => *value as f64,
+            Some(Value::Float(value)) => *value,
+            Some(value) => return Err(format!("`rate_limit.rate` is not a number: {value}")),
+        };
+        if !(rate.is_finite() && rate >= MIN_RATE) {
+            return Err(format!(
+                "`rate_limit.rate` must be at least {MIN_RATE} (a request a day): {rate}"
+            ));
+        }
+        let burst = match limit.get("burst") {
+            // Allow a second worth of requests at once
+            None => rate.ceil() as u32,
+            Some(Value::Integer(value)) => match u32::try_from(*value) {
+                Ok(burst) if burst >= 1 => burst,
+                _ => return Err(format!("`rate_limit.burst` must be at least 1: {value}")),
+            },
+            Some(value) => return Err(format!("`rate_limit.burst` is not an integer: {value}")),
+        };
+        Ok(Some(RateLimitSettings {
+            rate,
+            burst,
+            ipv4_prefix: get_prefix(limit, "ipv4_prefix", 32, 32)?,
+            ipv6_prefix: get_prefix(limit, "ipv6_prefix", 64, 128)?,
+        }))
+    }
+}
+
+/// Keeps the first `prefix` bits of the address
+fn subnet(ip: IpAddr, ipv4_prefix: u8, ipv6_prefix: u8) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let mask = u32::MAX.checked_shl(32 - ipv4_prefix as u32).unwrap_or(0);
+            IpAddr::V4(Ipv4Addr::from_bits(ip.to_bits() & mask))
+        }
+        IpAddr::V6(ip) => {
+            let mask = u128::MAX.checked_shl(128 - ipv6_prefix as u32).unwrap_or(0);
+            IpAddr::V6(Ipv6Addr::from_bits(ip.to_bits() & mask))
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct Buckets {
+    by_subnet: HashMap<IpAddr, Bucket>,
+    cleaned: Instant,
+}
+
+/// Token bucket per client subnet: every request takes a token,
+/// tokens are refilled at `rate` per second up to `burst`
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings) -> Self {
+        RateLimiter {
+            settings,
+            buckets: Mutex::new(Buckets {
+                by_subnet: HashMap::new(),
+                cleaned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token for a request from `ip`.
+    /// Returns the time until the next token if there are none left.
+    pub fn check(&self, ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        let RateLimitSettings {
+            rate,
+            burst,
+            ipv4_prefix,
+            ipv6_prefix,
+        } = self.settings;
+        let burst = burst as f64;
+        let mut buckets = match self.buckets.lock() {
+            Ok(buckets) => buckets,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        // A bucket that refilled completely is the same as a missing one
+        if now.saturating_duration_since(buckets.cleaned) >= CLEANUP_INTERVAL {
+            buckets.by_subnet.retain(|_, bucket| {
+                let idle = now.saturating_duration_since(bucket.updated);
+                bucket.tokens + idle.as_secs_f64() * rate < burst
+            });
+            buckets.cleaned = now;
+        }
+
+        let bucket = buckets
+            .by_subnet
+            .entry(subnet(ip, ipv4_prefix, ipv6_prefix))
+            .or_insert(Bucket {
+                tokens: burst,
+                updated: now,
+            });
+        let idle = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + idle.as_secs_f64() * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = Duration::try_from_secs_f64((1.0 - bucket.tokens) / rate);
+            Err(wait.unwrap_or(Duration::MAX))
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.buckets.lock().unwrap().by_subnet.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(rate: f64, burst: u32) -> RateLimiter {
+        RateLimiter::new(RateLimitSettings {
+            rate,
+            burst,
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+        })
+    }
+
+    #[test]
+    fn settings() {
+        const CONTENT: &str = "
+            [rate_limit]
+            rate = 0.5
+            burst = 10
+            ipv4_prefix = 24";
+        let table: Table = CONTENT.parse().unwrap();
+        assert_eq!(
+            Some(RateLimitSettings {
+                rate: 0.5,
+                burst: 10,
+                ipv4_prefix: 24,
+                ipv6_prefix: 64,
+            }),
+            RateLimitSettings::from_table(&table).unwrap()
+        );
+
+        let table: Table = "rate_limit = { rate = 5 }".parse().unwrap();
+        let settings = RateLimitSettings::from_table(&table).unwrap().unwrap();
+        assert_eq!((5, 32), (settings.burst, settings.ipv4_prefix));
+
+        assert_eq!(None, RateLimitSettings::from_table(&Table::new()).unwrap());
+    }
+
+    #[test]
+    fn invalid_settings() {
+        for content in [
+            "rate_limit = 5",
+            "rate_limit = { burst = 5 }",
+            "rate_limit = { rate = 0 }",
+            "rate_limit = { rate = -1.5 }",
+            "rate_limit = { rate = 1e-20 }",
+            "rate_limit = { rate = 5, burst = 0 }",
+            "rate_limit = { rate = 5, ipv4_prefix = 33 }",
+            "rate_limit = { rate = 5, ipv6_prefix = 129 }",
+        ] {
+            let table: Table = content.parse().unwrap();
+            assert!(RateLimitSettings::from_table(&table).is_err(), "{content}");
+        }
+    }
+
+    #[test]
+    fn subnets() {
+        let ip = |text: &str| text.parse::<IpAddr>().unwrap();
+        assert_eq!(ip("10.1.2.0"), subnet(ip("10.1.2.3"), 24, 64));
+        assert_eq!(ip("0.0.0.0"), subnet(ip("10.1.2.3"), 0, 64));
+        assert_eq!(ip("10.1.2.3"), subnet(ip("::ffff:10.1.2.3"), 32, 64));
+        assert_eq!(
+            ip("2001:db8:0:1::"),
+            subnet(ip("2001:db8:0:1:2:3:4:5"), 32, 64)
+        );
+    }
+
+    #[test]
+    fn bucket() {
+        let limiter = limiter(2.0, 3);
+        let (client, neighbour, other) = (
+            "10.0.0.1".parse().unwrap(),
+            "10.0.0.2".parse().unwrap(),
+            "10.0.1.1".parse().unwrap(),
+        );
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(Ok(()), limiter.check(client, start));
+        }
+        // Clients of one subnet share the bucket
+        assert_eq!(
+            Err(Duration::from_millis(500)),
+            limiter.check(neighbour, start)
+        );
+        assert_eq!(Ok(()), limiter.check(other, start));
+
+        // A token is refilled every half a second
+        let later = start + Duration::from_millis(600);
+        assert_eq!(Ok(()), limiter.check(client, later));
+        assert!(limiter.check(client, later).is_err());
+    }
+
+    #[test]
+    fn slow_rate() {
+        // Settings refuse such rates, the limiter does not overflow anyway
+        let limiter = limiter(1e-20, 1);
+        let ip = "10.0.0.1".parse().unwrap();
+        let start = Instant::now();
+        assert_eq!(Ok(()), limiter.check(ip, start));
+        assert_eq!(Err(Duration::MAX), limiter.check(ip, start));
+    }
+
+    #[test]
+    fn cleanup() {
+        let limiter = limiter(1.0, 5);
+        let start = Instant::now();
+        limiter.check("10.0.0.1".parse().unwrap(), start).unwrap();
+        limiter.check("10.0.1.1".parse().unwrap(), start).unwrap();
+        assert_eq!(2, limiter.len());
+
+        // Buckets of quiet clients are dropped, the active one stays
+        let later = start + CLEANUP_INTERVAL;
+        for _ in 0..5 {
+            let _ = limiter.check("10.0.2.1".parse().unwrap(), later);
+        }
+        assert_eq!(1, limiter.len());
+    }
+}
@@ -0,0 +1,666 @@
+use std::io::{self, ErrorKind, Read};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+const NEWLINE: &[u8] = b"\r\n";
+const HEADER_END: &[u8] = b"\r\n\r\n";
+
+#[derive(Debug)]
+pub enum ReadErr {
+    /// Message is malformed, the client may be told why
+    BadRequest(String),
+    /// Request line exceeds the limit
+    UriTooLong(String),
+    /// Header block exceeds the limit
+    HeaderTooLarge(String),
+    /// Body exceeds the limit
+    BodyTooLarge(String),
+    /// Client stopped sending in the middle of a message
+    TimedOut,
+    /// Stream failed
+    Io(String),
+}
+
+/// Stream that can limit time spent waiting for data
+pub trait TimedRead: Read {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl TimedRead for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+impl TimedRead for UnixStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum BodyLength {
+    Fixed(usize),
+    Chunked,
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    haystack
+        .get(from..)?
+        .windows(needle.len())
+        .position(|window| window == needle)
+        .map(|pos| pos + from)
+}
+
+fn body_length(head: &str) -> Result<BodyLength, ReadErr> {
+    let mut length = None;
+    let mut chunked = false;
+
+    for line in head.split("\r\n").skip(1) {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        if name.eq_ignore_ascii_case("Content-Length") {
+            let parsed = match value.bytes().all(|byte| byte.is_ascii_digit()) {
+                true => value.parse::<usize>().ok(),
+                false => None,
+            }
+            .ok_or(ReadErr::BadRequest("Invalid Content-Length".to_string()))?;
+            if length.is_some_and(|known| known != parsed) {
+                return Err(ReadErr::BadRequest(
+                    "Conflicting Content-Length".to_string(),
+                ));
+            }
+            length = Some(parsed);
+        } else if name.eq_ignore_ascii_case("Transfer-Encoding") {
+            // Chunked coding must be applied last, other codings are not supported
+            if !value.eq_ignore_ascii_case("chunked") {
+                return Err(ReadErr::BadRequest(
+                    "Unsupported Transfer-Encoding".to_string(),
+                ));
+            }
+            chunked = true;
+        }
+    }
+
+    match (length, chunked) {
+        (Some(_), true) => Err(ReadErr::BadRequest(
+            "Both Content-Length and Transfer-Encoding are set".to_string(),
+        )),
+        (None, true) => Ok(BodyLength::Chunked),
+        (Some(length), false) => Ok(BodyLength::Fixed(length)),
+        (None, false) => Ok(BodyLength::Fixed(0)),
+    }
+}
+
+/// Limits of time spent reading a message
+#[derive(Debug, Clone, Copy)]
+pub struct ReadTimeouts {
+    /// Time to wait for the first byte of a message
+    pub idle: Duration,
+    /// Time to wait for each read once a message started
+    pub read: Duration,
+    /// Time to receive the whole head once a message started
+    pub header: Duration,
+    /// Time to receive the whole body once the head is received
+    pub body: Duration,
+}
+
+/// Largest accepted parts of a message, in bytes unless noted
+#[derive(Debug, Clone, Copy)]
+pub struct ReadLimits {
+    /// Request line without the line break
+    pub request_line: usize,
+    /// Header lines after the request line, with line breaks.
+    /// Bounds each header line as well.
+    pub header: usize,
+    /// Number of header lines
+    pub header_lines: usize,
+    /// Decoded body
+    pub body: usize,
+}
+
+/// Reads whole HTTP messages from a stream, keeping pipelined data for later
+pub struct MessageReader<S: TimedRead> {
+    stream: S,
+    buffer: Vec<u8>,
+    timeouts: ReadTimeouts,
+    limits: ReadLimits,
+    /// End of the current stage of the message, head or body, unless it has not started
+    stage_deadline: Option<Instant>,
+    /// Time the connection must be done by
+    deadline: Option<Instant>,
+}
+
+impl<S: TimedRead> MessageReader<S> {
+    pub fn new(stream: S, timeouts: ReadTimeouts, limits: ReadLimits) -> Self {
+        MessageReader {
+            stream,
+            buffer: Vec::new(),
+            timeouts,
+            limits,
+            stage_deadline: None,
+            deadline: None,
+        }
+    }
+
+    /// Stops reading at `deadline`, whatever the stage of the message is
+    pub fn set_deadline(&mut self, deadline: Instant) {
+        self.deadline = Some(deadline);
+    }
+
+    /// Checks whether a part of the next message was already received
+    pub fn has_buffered(&self) -> bool {
+        !self.buffer.is_empty()
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Reads the next message: head and decoded body.
+    /// Returns `None` when the client closed the connection or stayed idle for too long.
+    pub fn read_message(&mut self) -> Result<Option<Vec<u8>>, ReadErr> {
+        // Pipelined message started already
+        self.stage_deadline = match self.buffer.is_empty() {
+            true => None,
+            false => Some(Instant::now() + self.timeouts.header),
+        };
+        let mut searched = 0;
+        let head_len = loop {
+            let head_end = find(&self.buffer, HEADER_END, searched);
+            self.check_head(head_end)?;
+            if let Some(pos) = head_end {
+                break pos + HEADER_END.len();
+            }
+            searched = self.buffer.len().saturating_sub(HEADER_END.len() - 1);
+            match self.fill() {
+                Ok(true) => continue,
+                Ok(false) | Err(ReadErr::TimedOut) if self.buffer.is_empty() => return Ok(None),
+                Ok(false) => return Err(Self::incomplete()),
+                Err(err) => return Err(err),
+            }
+        };
+
+        self.stage_deadline = Some(Instant::now() + self.timeouts.body);
+        let head = String::from_utf8_lossy(&self.buffer[..head_len]).into_owned();
+        let message = match body_length(&head)? {
+            BodyLength::Fixed(length) if length > self.limits.body => {
+                return Err(self.body_too_large());
+            }
+            BodyLength::Fixed(length) => {
+                let end = head_len
+                    .checked_add(length)
+                    .ok_or(ReadErr::BadRequest("Invalid Content-Length".to_string()))?;
+                self.fill_to(end)?;
+                self.buffer.drain(..end).collect()
+            }
+            BodyLength::Chunked => {
+                let (body, end) = self.read_chunked(head_len)?;
+                let mut message: Vec<u8> = self.buffer.drain(..end).take(head_len).collect();
+                message.extend(body);
+                message
+            }
+        };
+        Ok(Some(message))
+    }
+
+    /// Checks the received part of the head against the limits,
+    /// `head_end` is the start of the empty line if it was received
+    fn check_head(&self, head_end: Option<usize>) -> Result<(), ReadErr> {
+        let ReadLimits {
+            request_line,
+            header,
+            header_lines,
+            ..
+        } = self.limits;
+        // Line break may be received with the next read
+        let line_end = find(&self.buffer, NEWLINE, 0);
+        let line_len = line_end.unwrap_or(self.buffer.len().saturating_sub(1));
+        if line_len > request_line {
+            return Err(ReadErr::UriTooLong(format!(
+                "Request line is longer than {request_line} bytes"
+            )));
+        }
+        if let Some(line_end) = line_end {
+            // Header lines end with the first line break of the empty line
+            let header_end = head_end.map_or(self.buffer.len(), |end| end + NEWLINE.len());
+            let header_len = header_end.saturating_sub(line_end + NEWLINE.len());
+            if header_len > header {
+                return Err(ReadErr::HeaderTooLarge(format!(
+                    "Header fields are longer than {header} bytes"
+                )));
+            }
+            let lines = self.buffer[line_end + NEWLINE.len()..header_end]
+                .windows(NEWLINE.len())
+                .filter(|window| *window == NEWLINE)
+                .count();
+            if lines > header_lines {
+                return Err(ReadErr::HeaderTooLarge(format!(
+                    "More than {header_lines} header lines"
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn body_too_large(&self) -> ReadErr {
+        ReadErr::BodyTooLarge(format!("Body is longer than {} bytes", self.limits.body))
+    }
+
+    /// Decodes chunked body starting at `start`.
+    /// Returns the body and the end of the message in the buffer.
+    fn read_chunked(&mut self, start: usize) -> Result<(Vec<u8>, usize), ReadErr> {
+        let mut body = Vec::new();
+        let mut pos = start;
+        loop {
+            let line_end = self.fill_to_line(pos)?;
+            let line = String::from_utf8_lossy(&self.buffer[pos..line_end]);
+            let size_str = match line.split_once(';') {
+                Some((size, _extensions)) => size,
+                None => &line,
+            };
+            let size = usize::from_str_radix(size_str.trim(), 16)
+                .map_err(|_err| ReadErr::BadRequest("Invalid chunk size".to_string()))?;
+            pos = line_end + NEWLINE.len();
+
+            if size == 0 {
+                break;
+            }
+
+            if size > self.limits.body - body.len() {
+                return Err(self.body_too_large());
+            }
+            let data_end = pos
+                .checked_add(size)
+                .ok_or(ReadErr::BadRequest("Invalid chunk size".to_string()))?;
+            self.fill_to(data_end + NEWLINE.len())?;
+            if &self.buffer[data_end..data_end + NEWLINE.len()] != NEWLINE {
+                return Err(ReadErr::BadRequest("Missing chunk terminator".to_string()));
+            }
+            body.extend_from_slice(&self.buffer[pos..data_end]);
+            pos = data_end + NEWLINE.len();
+        }
+
+        // Trailer fields are skipped up to the empty line
+        loop {
+            let line_end = self.fill_to_line(pos)?;
+            let empty = line_end == pos;
+            pos = line_end + NEWLINE.len();
+            if empty {
+                break;
+            }
+        }
+        Ok((body, pos))
+    }
+
+    fn incomplete() -> ReadErr {
+        ReadErr::BadRequest("Incomplete message".to_string())
+    }
+
+    /// Reads until the buffer holds at least `len` bytes
+    fn fill_to(&mut self, len: usize) -> Result<(), ReadErr> {
+        while self.buffer.len() < len {
+            if !self.fill()? {
+                return Err(Self::incomplete());
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads until a line starting at `from` is complete, returns the line end.
+    /// Chunk size and trailer lines may be as long as header fields.
+    fn fill_to_line(&mut self, from: usize) -> Result<usize, ReadErr> {
+        loop {
+            if let Some(pos) = find(&self.buffer, NEWLINE, from) {
+                return Ok(pos);
+            }
+            if self.buffer.len() - from > self.limits.header {
+                return Err(ReadErr::BadRequest(format!(
+                    "Chunk line is longer than {} bytes",
+                    self.limits.header
+                )));
+            }
+            if !self.fill()? {
+                return Err(Self::incomplete());
+            }
+        }
+    }
+
+    /// Finds how long the next read may wait, fails if the time is up
+    fn read_timeout(&self) -> Result<Duration, ReadErr> {
+        let now = Instant::now();
+        let mut timeout = match self.stage_deadline {
+            None => self.timeouts.idle,
+            Some(deadline) => self
+                .timeouts
+                .read
+                .min(deadline.saturating_duration_since(now)),
+        };
+        if let Some(deadline) = self.deadline {
+            timeout = timeout.min(deadline.saturating_duration_since(now));
+        }
+        // Zero timeout would block forever
+        match timeout.is_zero() {
+            true => Err(ReadErr::TimedOut),
+            false => Ok(timeout),
+        }
+    }
+
+    /// Reads available data into the buffer, returns `false` on end of stream
+    fn fill(&mut self) -> Result<bool, ReadErr> {
+        const BUFFER_SIZE: usize = 4096;
+        let timeout = self.read_timeout()?;
+        self.stream
+            .set_read_timeout(Some(timeout))
+            .map_err(|err| ReadErr::Io(err.to_string()))?;
+
+        let mut chunk = [0; BUFFER_SIZE];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(read_count) => {
+                    self.buffer.extend_from_slice(&chunk[..read_count]);
+                    // First bytes of a message start the time for its head
+                    if self.stage_deadline.is_none() {
+                        self.stage_deadline = Some(Instant::now() + self.timeouts.header);
+                    }
+                    return Ok(true);
+                }
+                Err(err) => match err.kind() {
+                    ErrorKind::Interrupted => continue,
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => return Err(ReadErr::TimedOut),
+                    _ => return Err(ReadErr::Io(err.to_string())),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    /// Stream that returns data in fixed segments, like a slow client would
+    struct Segments(VecDeque<Result<Vec<u8>, ErrorKind>>);
+
+    impl Segments {
+        fn new(segments: &[&str]) -> Self {
+            Segments(
+                segments
+                    .iter()
+                    .map(|segment| Ok(segment.as_bytes().to_vec()))
+                    .collect(),
+            )
+        }
+    }
+
+    impl Read for Segments {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Ok(segment)) => {
+                    buf[..segment.len()].copy_from_slice(&segment);
+                    Ok(segment.len())
+                }
+                Some(Err(kind)) => Err(io::Error::from(kind)),
+                None => Ok(0),
+            }
+        }
+    }
+
+    impl TimedRead for Segments {
+        fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const TIMEOUTS: ReadTimeouts = ReadTimeouts {
+        idle: Duration::from_secs(1),
+        read: Duration::from_secs(1),
+        header: Duration::from_secs(1),
+        body: Duration::from_secs(1),
+    };
+
+    const LIMITS: ReadLimits = ReadLimits {
+        request_line: 64,
+        header: 64,
+        header_lines: 4,
+        body: 16,
+    };
+
+    fn reader(segments: Segments) -> MessageReader<Segments> {
+        MessageReader::new(segments, TIMEOUTS, LIMITS)
+    }
+
+    fn read_str(reader: &mut MessageReader<Segments>) -> Option<String> {
+        reader
+            .read_message()
+            .unwrap()
+            .map(|message| String::from_utf8(message).unwrap())
+    }
+
+    #[test]
+    fn split_head() {
+        let mut reader = reader(Segments::new(&[
+            "GET /a HT",
+            "TP/1.1\r\nHost: a\r",
+            "\n\r\n",
+        ]));
+        assert_eq!(
+            Some("GET /a HTTP/1.1\r\nHost: a\r\n\r\n".to_string()),
+            read_str(&mut reader)
+        );
+        assert_eq!(None, read_str(&mut reader));
+    }
+
+    #[test]
+    fn pipelined() {
+        let mut reader = reader(Segments::new(&[
+            "GET /a HTTP/1.1\r\n\r\nPOST /b HTTP/1.1\r\nContent-Length: 4\r\n\r\nBO",
+            "DYGET /c HTTP/1.1\r\n\r\n",
+        ]));
+        assert_eq!(
+            Some("GET /a HTTP/1.1\r\n\r\n".to_string()),
+            read_str(&mut reader)
+        );
+        assert_eq!(
+            Some("POST /b HTTP/1.1\r\nContent-Length: 4\r\n\r\nBODY".to_string()),
+            read_str(&mut reader)
+        );
+        assert_eq!(
+            Some("GET /c HTTP/1.1\r\n\r\n".to_string()),
+            read_str(&mut reader)
+        );
+        assert_eq!(None, read_str(&mut reader));
+    }
+
+    #[test]
+    fn chunked() {
+        let mut reader = reader(Segments::new(&[
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "4;ext=1\r\nWiki\r\n",
+            "5\r\npedia\r\n0\r\nTrailer: value\r\n\r\nGET / HTTP/1.1\r\n\r\n",
+        ]));
+        assert_eq!(
+            Some("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\nWikipedia".to_string()),
+            read_str(&mut reader)
+        );
+        assert_eq!(
+            Some("GET / HTTP/1.1\r\n\r\n".to_string()),
+            read_str(&mut reader)
+        );
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let mut reader = reader(Segments(VecDeque::from([Err(ErrorKind::WouldBlock)])));
+        assert_eq!(None, read_str(&mut reader));
+    }
+
+    #[test]
+    fn read_timeout() {
+        let mut reader = reader(Segments(VecDeque::from([
+            Ok(b"GET / HTTP/1.1\r\n".to_vec()),
+            Err(ErrorKind::TimedOut),
+        ])));
+        let error = reader.read_message().unwrap_err();
+        assert!(matches!(error, ReadErr::TimedOut), "{:?}", error);
+    }
+
+    /// Stream that sleeps before returning each segment, like a slowloris client would
+    struct Slow(Segments, Duration);
+
+    impl Read for Slow {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            std::thread::sleep(self.1);
+            self.0.read(buf)
+        }
+    }
+
+    impl TimedRead for Slow {
+        fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn stage_timeouts() {
+        const DELAY: Duration = Duration::from_millis(20);
+        let timeouts = ReadTimeouts {
+            header: DELAY * 3,
+            body: DELAY * 3,
+            ..TIMEOUTS
+        };
+        let slow = |segments: &[&str]| Slow(Segments::new(segments), DELAY);
+
+        // Each read is quick enough, but the head takes too long
+        let mut reader = MessageReader::new(slow(&["GET / HTTP/1.1\r\n"; 10]), timeouts, LIMITS);
+        let error = reader.read_message().unwrap_err();
+        assert!(matches!(error, ReadErr::TimedOut), "{:?}", error);
+
+        // Same for the body
+        let mut reader = MessageReader::new(
+            slow(&[
+                "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n",
+                "B",
+                "O",
+                "D",
+                "Y",
+                "B",
+                "O",
+                "D",
+                "Y",
+            ]),
+            timeouts,
+            LIMITS,
+        );
+        let error = reader.read_message().unwrap_err();
+        assert!(matches!(error, ReadErr::TimedOut), "{:?}", error);
+
+        // Connection deadline cuts the head short as well
+        let mut reader =
+            MessageReader::new(slow(&["GET / HTTP/1.1\r\n", "\r\n"]), TIMEOUTS, LIMITS);
+        reader.set_deadline(Instant::now() + DELAY / 2);
+        let error = reader.read_message().unwrap_err();
+        assert!(matches!(error, ReadErr::TimedOut), "{:?}", error);
+    }
+
+    #[test]
+    fn limits() {
+        let long_target = format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(64));
+        let long_header = format!("GET / HTTP/1.1\r\nX-Long: {}\r\n\r\n", "a".repeat(60));
+        let chunked = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let long_chunk_line = "0".repeat(100);
+        let many_lines = format!("GET / HTTP/1.1\r\n{}\r\n", "A: b\r\n".repeat(5));
+        let cases: [(&[&str], &str); 8] = [
+            (&[&long_target], "UriTooLong"),
+            // Request line that never ends is cut short as well
+            (&[&long_target[..40], &long_target[40..70]], "UriTooLong"),
+            (&[&long_header], "HeaderTooLarge"),
+            // Header line that never ends as well
+            (
+                &[&long_header[..50], &long_header[50..84]],
+                "HeaderTooLarge",
+            ),
+            (&[&many_lines], "HeaderTooLarge"),
+            (
+                &["GET / HTTP/1.1\r\nContent-Length: 17\r\n\r\n"],
+                "BodyTooLarge",
+            ),
+            (
+                &[chunked, "8\r\n12345678\r\n9\r\n123456789\r\n0\r\n\r\n"],
+                "BodyTooLarge",
+            ),
+            (&[chunked, &long_chunk_line], "BadRequest"),
+        ];
+        for (segments, expected) in cases {
+            let error = reader(Segments::new(segments)).read_message().unwrap_err();
+            assert!(
+                format!("{error:?}").starts_with(expected),
+                "{segments:?}: {error:?}"
+            );
+        }
+
+        // Parts as long as the limits are fine
+        let message = format!(
+            "GET /{} HTTP/1.1\r\nX-Long: {}\r\nContent-Length: 16\r\n\r\n{}",
+            "a".repeat(49),
+            "a".repeat(28),
+            "b".repeat(16)
+        );
+        assert_eq!(
+            Some(message.clone()),
+            read_str(&mut reader(Segments::new(&[&message])))
+        );
+    }
+
+    #[test]
+    fn incomplete() {
+        const MESSAGES: [&str; 3] = [
+            "GET / HTTP/1.1\r\n",
+            "POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nBODY",
+            "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nBODY\r\n",
+        ];
+        for message in MESSAGES {
+            let error = reader(Segments::new(&[message]))
+                .read_message()
+                .unwrap_err();
+            assert!(
+                matches!(error, ReadErr::BadRequest(ref err) if err == "Incomplete message"),
+                "{:?}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_framing() {
+        const MESSAGES: [(&str, &str); 5] = [
+            ("Content-Length: -1", "Invalid Content-Length"),
+            (
+                "Content-Length: 1\r\nContent-Length: 2",
+                "Conflicting Content-Length",
+            ),
+            ("Transfer-Encoding: gzip", "Unsupported Transfer-Encoding"),
+            (
+                "Transfer-Encoding: chunked\r\nContent-Length: 1",
+                "Both Content-Length and Transfer-Encoding are set",
+            ),
+            ("Transfer-Encoding: chunked\r\n\r\nZ", "Invalid chunk size"),
+        ];
+        for (headers, expected) in MESSAGES {
+            let message = format!("POST / HTTP/1.1\r\n{headers}\r\n\r\n");
+            let error = reader(Segments::new(&[&message]))
+                .read_message()
+                .unwrap_err();
+            assert!(
+                matches!(error, ReadErr::BadRequest(ref err) if err == expected),
+                "{:?}",
+                error
+            );
+        }
+    }
+}
@@ -2,61 +2,251 @@
 pub enum QueryErr {
     BadRequest(String),
     MethodNotAllowed,
+    /// Header line is not a valid `name: value` field
+    MalformedHeader(String),
+    /// Header line, header block or the number of header lines exceeds the limit
+    HeaderTooLarge(String),
+    /// Request line exceeds the limit
+    UriTooLong(String),
+    /// Body exceeds the limit
+    ContentTooLarge(String),
+    /// Body is not a form
+    UnsupportedMediaType(String),
 }
 
-pub fn parse_query(request: &str) -> Result<Vec<String>, QueryErr> {
-    const NEWLINE: &str = "\r\n";
-    let (header, _body) = match request.split_once(&format!("{NEWLINE}{NEWLINE}")) {
-        Some(v) => v,
-        None => {
-            return Err(QueryErr::BadRequest(
-                "Missing header-body split".to_string(),
-            ));
-        }
-    };
-
-    let (start_line, _headers_block) = match header.split_once(NEWLINE) {
-        Some(v) => v,
-        None => (header, ""), // headers might be omitted
-    };
+/// Path that takes search terms only from the query string or a form
+pub const SEARCH_PATH: &str = "search";
+/// Media type of `POST` bodies with search terms
+pub const FORM_TYPE: &str = "application/x-www-form-urlencoded";
+
+/// Header fields in order of appearance, names are case-insensitive
+#[derive(Debug, Default)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// Gets the first value of the header
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
 
-    let (method, rest_start_line) = match start_line.split_once(' ') {
-        Some(v) => v,
-        None => return Err(QueryErr::BadRequest("Invalid start-line".to_string())),
-    };
+    /// Number of header lines
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
 
-    const ALLOWED_METHODS: [&str; 2] = ["GET", "HEAD"];
-    if !ALLOWED_METHODS.contains(&method) {
-        return Err(QueryErr::MethodNotAllowed);
+    /// Gets all values of the header, a header may be sent several times
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        self.0
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
     }
+}
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// Target with the leading slash and the query string
+    pub target: String,
+    /// Protocol version, empty if omitted
+    pub version: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
 
-    let (url, proto) = match rest_start_line.split_once(' ') {
-        Some(v) => v,
-        None => (rest_start_line, ""), // protocol may be omitted
-    };
+/// Checks whether text is a token: method or header name
+fn is_token(text: &str) -> bool {
+    const SPECIAL: &str = "!#$%&'*+-.^_`|~";
+    !text.is_empty()
+        && text
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || SPECIAL.contains(ch))
+}
 
-    if !proto.is_empty() && !proto.starts_with("HTTP") {
-        return Err(QueryErr::BadRequest("Invalid protocol".to_string()));
+fn parse_header(line: &str) -> Result<(String, String), QueryErr> {
+    let malformed = || QueryErr::MalformedHeader(format!("Malformed header line: `{line}`"));
+    let (name, value) = line.split_once(':').ok_or_else(malformed)?;
+    // Names may not be followed by whitespace, folded lines are obsolete
+    if !is_token(name) {
+        return Err(malformed());
     }
+    let value = value.trim_matches([' ', '\t']);
+    if value.chars().any(|ch| ch.is_control() && ch != '\t') {
+        return Err(malformed());
+    }
+    Ok((name.to_string(), value.to_string()))
+}
 
-    let encoded = match url.split_at_checked(1) {
-        Some((slash, rest)) => {
-            if slash == "/" {
-                rest
-            } else {
+impl Request {
+    pub fn parse(message: &[u8]) -> Result<Request, QueryErr> {
+        const NEWLINE: &str = "\r\n";
+        const HEADER_END: &[u8] = b"\r\n\r\n";
+        let split = match message
+            .windows(HEADER_END.len())
+            .position(|window| window == HEADER_END)
+        {
+            Some(v) => v,
+            None => {
                 return Err(QueryErr::BadRequest(
-                    "Missing leading slash in target".to_string(),
+                    "Missing header-body split".to_string(),
                 ));
             }
+        };
+        let header = std::str::from_utf8(&message[..split])
+            .map_err(|_err| QueryErr::BadRequest("Header is not valid UTF-8".to_string()))?;
+        let body = message[split + HEADER_END.len()..].to_vec();
+
+        let (start_line, headers_block) = match header.split_once(NEWLINE) {
+            Some(v) => v,
+            None => (header, ""), // headers might be omitted
+        };
+
+        let (method, rest_start_line) = match start_line.split_once(' ') {
+            Some((method, rest)) if is_token(method) => (method, rest),
+            _ => return Err(QueryErr::BadRequest("Invalid start-line".to_string())),
+        };
+
+        let (target, version) = match rest_start_line.split_once(' ') {
+            Some(v) => v,
+            None => (rest_start_line, ""), // protocol may be omitted
+        };
+
+        if !version.is_empty() && !version.starts_with("HTTP") {
+            return Err(QueryErr::BadRequest("Invalid protocol".to_string()));
         }
-        None => {
+
+        if !target.starts_with('/') {
             return Err(QueryErr::BadRequest(
                 "Missing leading slash in target".to_string(),
             ));
         }
-    };
 
-    decode(encoded).map_err(|err| QueryErr::BadRequest(err))
+        let mut headers = Vec::new();
+        for line in headers_block.split(NEWLINE).filter(|line| !line.is_empty()) {
+            headers.push(parse_header(line)?);
+        }
+
+        Ok(Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: version.to_string(),
+            headers: Headers(headers),
+            body,
+        })
+    }
+
+    /// Gets the path of the target, without the leading slash and the query string
+    pub fn path(&self) -> &str {
+        split_target(&self.target[1..]).0
+    }
+
+    /// Gets the query string of the target
+    pub fn query_string(&self) -> Option<&str> {
+        split_target(&self.target[1..]).1
+    }
+
+    /// Checks whether the connection should stay open after answering the request.
+    /// HTTP/1.1 connections are persistent unless `Connection: close` is sent,
+    /// HTTP/1.0 connections are closed unless `Connection: keep-alive` is sent.
+    pub fn keep_alive(&self) -> bool {
+        let mut persistent = match self.version.as_str() {
+            "HTTP/1.1" => true,
+            "HTTP/1.0" => false,
+            _ => return false,
+        };
+        for value in self.headers.get_all("Connection") {
+            for option in value.split(',').map(str::trim) {
+                if option.eq_ignore_ascii_case("close") {
+                    return false;
+                } else if option.eq_ignore_ascii_case("keep-alive") {
+                    persistent = true;
+                }
+            }
+        }
+        persistent
+    }
+}
+
+/// Extracts search terms from the request target or a form.
+/// Terms are taken from `query_param` of the query string, e.g. `/search?q=terms`.
+/// Otherwise the whole path is used as search terms, e.g. `/terms`.
+/// `POST` requests take them from `form_field` of the body, e.g. `q=terms`.
+pub fn parse_query(
+    request: &Request,
+    query_param: &str,
+    form_field: &str,
+) -> Result<Vec<String>, QueryErr> {
+    const ALLOWED_METHODS: [&str; 3] = ["GET", "HEAD", "POST"];
+    if !ALLOWED_METHODS.contains(&request.method.as_str()) {
+        return Err(QueryErr::MethodNotAllowed);
+    }
+    if request.method == "POST" {
+        return parse_form(request, form_field);
+    }
+
+    if let Some(terms) = parse_param(request, query_param)? {
+        return Ok(terms);
+    }
+    let path = request.path();
+    if path == SEARCH_PATH {
+        return Err(QueryErr::BadRequest(format!(
+            "Missing `{query_param}` parameter"
+        )));
+    }
+
+    decode(path).map_err(QueryErr::BadRequest)
+}
+
+/// Extracts a parameter from the query string of the request target,
+/// e.g. `terms` from `/suggest?q=terms`
+pub fn parse_param(request: &Request, name: &str) -> Result<Option<Vec<String>>, QueryErr> {
+    match request.query_string() {
+        Some(query_string) => find_param(query_string, name).map_err(QueryErr::BadRequest),
+        None => Ok(None),
+    }
+}
+
+/// Extracts search terms from `field` of a form body
+fn parse_form(request: &Request, field: &str) -> Result<Vec<String>, QueryErr> {
+    let content_type = request.headers.get("Content-Type").unwrap_or("");
+    // Parameters such as `charset` do not change the encoding of a form
+    let media_type = content_type.split(';').next().unwrap_or("").trim();
+    if !media_type.eq_ignore_ascii_case(FORM_TYPE) {
+        return Err(QueryErr::UnsupportedMediaType(format!(
+            "Content-Type is not {FORM_TYPE}: {content_type:?}"
+        )));
+    }
+    let body = str::from_utf8(&request.body)
+        .map_err(|err| QueryErr::BadRequest(format!("Form is not valid UTF-8: {err}")))?;
+    find_param(body, field)
+        .map_err(QueryErr::BadRequest)?
+        .ok_or(QueryErr::BadRequest(format!("Missing `{field}` field")))
+}
+
+/// Splits request target into path and query string
+fn split_target(target: &str) -> (&str, Option<&str>) {
+    match target.split_once('?') {
+        Some((path, query_string)) => (path, Some(query_string)),
+        None => (target, None),
+    }
+}
+
+/// Finds a parameter in the query string and decodes its value
+fn find_param(query_string: &str, name: &str) -> Result<Option<Vec<String>>, String> {
+    for pair in query_string.split('&') {
+        let (key, value) = match pair.split_once('=') {
+            Some(v) => v,
+            None => (pair, ""),
+        };
+        // Keys of other parameters need not decode, only the value found matters
+        if decode(key).is_ok_and(|key| key.join(" ") == name) {
+            return decode(value).map(Some);
+        }
+    }
+    Ok(None)
 }
 
 enum State {
@@ -107,7 +297,7 @@ impl State {
     fn flush(&self) -> Decoded {
         match self {
             State::None => Decoded::None,
-            State::Percent => Decoded::Byte('%' as u8),
+            State::Percent => Decoded::Byte(b'%'),
             State::Half(byte) => Decoded::FailedPercent(*byte),
         }
     }
@@ -115,7 +305,7 @@ impl State {
 
 impl Decoded {
     fn put_into(&self, utf8_parts: &mut Vec<Vec<u8>>) {
-        const PERCENT: u8 = '%' as u8;
+        const PERCENT: u8 = b'%';
         match self {
             Decoded::Byte(byte) => match &mut utf8_parts.last_mut() {
                 Some(part) => part.push(*byte),
@@ -196,6 +386,115 @@ mod tests {
         }
     }
 
+    fn parse(request: &str) -> Request {
+        Request::parse(request.as_bytes()).unwrap()
+    }
+
+    fn query(request: &str, query_param: &str) -> Result<Vec<String>, QueryErr> {
+        parse_query(&Request::parse(request.as_bytes())?, query_param, "f")
+    }
+
+    mod keep_alive {
+        use super::*;
+
+        #[test]
+        fn http11_default() {
+            assert!(parse("GET / HTTP/1.1\r\nHost: a\r\n\r\n").keep_alive());
+        }
+
+        #[test]
+        fn http11_close() {
+            assert!(
+                !parse("GET / HTTP/1.1\r\nConnection: Upgrade\r\nconnection: Close\r\n\r\n")
+                    .keep_alive()
+            );
+        }
+
+        #[test]
+        fn http10_default() {
+            assert!(!parse("GET / HTTP/1.0\r\n\r\n").keep_alive());
+        }
+
+        #[test]
+        fn http10_keep_alive() {
+            assert!(parse("GET / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n").keep_alive());
+        }
+
+        #[test]
+        fn unknown_version() {
+            for request in ["GET /\r\n\r\n", "GET / HTTPSOMETHING\r\n\r\n"] {
+                assert!(!parse(request).keep_alive(), "{:?}", request);
+            }
+        }
+    }
+
+    mod headers {
+        use super::*;
+
+        #[test]
+        fn success() {
+            let request = parse(
+                "POST /path?x=1 HTTP/1.1\r\nHost: banger\r\nX-Multi:a\r\nx-multi: \t b c \r\n\r\nBODY",
+            );
+            assert_eq!("POST", request.method);
+            assert_eq!("/path?x=1", request.target);
+            assert_eq!("path", request.path());
+            assert_eq!(Some("x=1"), request.query_string());
+            assert_eq!("HTTP/1.1", request.version);
+            assert_eq!(Some("banger"), request.headers.get("host"));
+            assert_eq!(
+                vec!["a", "b c"],
+                request.headers.get_all("X-MULTI").collect::<Vec<_>>()
+            );
+            assert_eq!(None, request.headers.get("Missing"));
+            assert_eq!(b"BODY".to_vec(), request.body);
+        }
+
+        #[test]
+        fn malformed() {
+            const LINES: [&str; 5] = [
+                "No colon",
+                ": empty name",
+                "Space : before colon",
+                " folded: line",
+                "Control: \x01",
+            ];
+            for line in LINES {
+                let request = format!("GET / HTTP/1.1\r\n{line}\r\n\r\n");
+                let parse_error = Request::parse(request.as_bytes()).unwrap_err();
+                assert!(
+                    matches!(parse_error, QueryErr::MalformedHeader(_)),
+                    "{:?}",
+                    parse_error
+                );
+            }
+        }
+
+        #[test]
+        fn large() {
+            // Sizes are limited while reading, as configured
+            let long_line = format!("GET / HTTP/1.1\r\nLong: {}\r\n\r\n", "a".repeat(8192));
+            let request = Request::parse(long_line.as_bytes()).unwrap();
+            assert_eq!(Some(8192), request.headers.get("Long").map(str::len));
+            let many_lines = format!("GET / HTTP/1.1\r\n{}\r\n", "A: b\r\n".repeat(101));
+            let request = Request::parse(many_lines.as_bytes()).unwrap();
+            assert_eq!(101, request.headers.len());
+        }
+
+        #[test]
+        fn invalid_utf8() {
+            let parse_error = Request::parse(b"GET /\xFF HTTP/1.1\r\n\r\n").unwrap_err();
+            assert!(
+                matches!(
+                        parse_error,
+                        QueryErr::BadRequest(ref err) if err == "Header is not valid UTF-8"
+                ),
+                "{:?}",
+                parse_error
+            );
+        }
+    }
+
     mod request {
         use super::*;
 
@@ -207,7 +506,7 @@ mod tests {
             const HEADERS: [&str; 2] = ["Header1: Value1\r\nHeader2: Value2", ""];
             const BODIES: [&str; 2] = ["BODY", ""];
 
-            let query = vec!["hello".to_string(), "привіт".to_string()];
+            let expected = vec!["hello".to_string(), "привіт".to_string()];
 
             for method in METHODS {
                 for protocol in PROTOCOLS {
@@ -215,17 +514,92 @@ mod tests {
                         for body in BODIES {
                             let request =
                                 format!("{method} {TARGET} {protocol}\r\n{headers}\r\n\r\n{body}");
-                            assert_eq!(query, parse_query(&request).unwrap());
+                            assert_eq!(expected, query(&request, "q").unwrap());
                         }
                     }
                 }
             }
         }
 
+        #[test]
+        fn query_string() {
+            const TARGETS: [&str; 4] = [
+                "/search?q=hello+%D0%BF%D1%80%D0%B8%D0%B2%D1%96%D1%82",
+                "/search?other=1&q=hello%20%D0%BF%D1%80%D0%B8%D0%B2%D1%96%D1%82&q=ignored",
+                "/?q=hello+%D0%BF%D1%80%D0%B8%D0%B2%D1%96%D1%82",
+                "/ignored?%71=hello+%D0%BF%D1%80%D0%B8%D0%B2%D1%96%D1%82",
+            ];
+            let expected = vec!["hello".to_string(), "привіт".to_string()];
+            for target in TARGETS {
+                let request = format!("GET {target} HTTP/1.1\r\n\r\n");
+                assert_eq!(expected, query(&request, "q").unwrap(), "{target}");
+            }
+        }
+
+        #[test]
+        fn undecodable_key() {
+            const REQUEST: &str = "GET /search?%ff=1&%zz=2&q=foo HTTP/1.1\r\n\r\n";
+            assert_eq!(vec!["foo".to_string()], query(REQUEST, "q").unwrap());
+            let parse_error = query("GET /search?%ff=1&q=%ff HTTP/1.1\r\n\r\n", "q").unwrap_err();
+            assert!(
+                matches!(parse_error, QueryErr::BadRequest(_)),
+                "{:?}",
+                parse_error
+            );
+        }
+
+        #[test]
+        fn custom_param() {
+            const REQUEST: &str = "GET /search?q=ignored&terms=hello+world HTTP/1.1\r\n\r\n";
+            assert_eq!(
+                vec!["hello".to_string(), "world".to_string()],
+                query(REQUEST, "terms").unwrap()
+            );
+        }
+
+        #[test]
+        fn legacy_path() {
+            const REQUEST: &str = "GET /hello+world?other=1 HTTP/1.1\r\n\r\n";
+            assert_eq!(
+                vec!["hello".to_string(), "world".to_string()],
+                query(REQUEST, "q").unwrap()
+            );
+        }
+
+        #[test]
+        fn missing_param() {
+            for target in ["/search", "/search?other=1"] {
+                let request = format!("GET {target} HTTP/1.1\r\n\r\n");
+                let parse_error = query(&request, "q").unwrap_err();
+                assert!(
+                    matches!(
+                            parse_error,
+                            QueryErr::BadRequest(ref err) if err == "Missing `q` parameter"
+                    ),
+                    "{:?}",
+                    parse_error
+                );
+            }
+        }
+
+        #[test]
+        fn param() {
+            const REQUEST: &str = "GET /suggest?q=hello+%21w HTTP/1.1\r\n\r\n";
+            assert_eq!(
+                Some(vec!["hello".to_string(), "!w".to_string()]),
+                parse_param(&parse(REQUEST), "q").unwrap()
+            );
+            assert_eq!(None, parse_param(&parse(REQUEST), "other").unwrap());
+            assert_eq!(
+                None,
+                parse_param(&parse("GET /suggest HTTP/1.1\r\n\r\n"), "q").unwrap()
+            );
+        }
+
         #[test]
         fn no_body_split() {
             const REQUEST: &str = "GET /target HTTP/1.1\r\nHeader: Value";
-            let parse_error = parse_query(REQUEST).unwrap_err();
+            let parse_error = query(REQUEST, "q").unwrap_err();
             assert!(
                 matches!(
                         parse_error,
@@ -239,7 +613,7 @@ mod tests {
         #[test]
         fn invalid_start_line() {
             const REQUEST: &str = "invalid_start-line\r\n\r\n";
-            let parse_error = parse_query(REQUEST).unwrap_err();
+            let parse_error = query(REQUEST, "q").unwrap_err();
             assert!(
                 matches!(
                         parse_error,
@@ -253,7 +627,7 @@ mod tests {
         #[test]
         fn method_not_allowed() {
             const REQUEST: &str = "INVALID /target HTTP/1.1\r\n\r\n";
-            let parse_error = parse_query(REQUEST).unwrap_err();
+            let parse_error = query(REQUEST, "q").unwrap_err();
             assert!(
                 matches!(parse_error, QueryErr::MethodNotAllowed),
                 "{:?}",
@@ -264,7 +638,7 @@ mod tests {
         #[test]
         fn invalid_protocol() {
             const REQUEST: &str = "GET /target INVALID\r\n\r\n";
-            let parse_error = parse_query(REQUEST).unwrap_err();
+            let parse_error = query(REQUEST, "q").unwrap_err();
             assert!(
                 matches!(
                         parse_error,
@@ -280,7 +654,7 @@ mod tests {
             const REQUEST1: &str = "GET target HTTP/1.1\r\n\r\n";
             const REQUEST2: &str = "GET target HTTP/1.1\r\n\r\n";
             for request in [REQUEST1, REQUEST2] {
-                let parse_error = parse_query(request).unwrap_err();
+                let parse_error = query(request, "q").unwrap_err();
                 assert!(
                     matches!(
                             parse_error,
@@ -292,4 +666,71 @@ mod tests {
             }
         }
     }
+
+    mod form {
+        use super::*;
+
+        fn post(content_type: &str, body: &str) -> Result<Vec<String>, QueryErr> {
+            query(
+                &format!(
+                    "POST /search?q=ignored HTTP/1.1\r\nContent-Type: {content_type}\r\n\r\n{body}"
+                ),
+                "q",
+            )
+        }
+
+        #[test]
+        fn success() {
+            const CONTENT_TYPES: [&str; 3] = [
+                "application/x-www-form-urlencoded",
+                "Application/X-WWW-Form-Urlencoded; charset=UTF-8",
+                " application/x-www-form-urlencoded ",
+            ];
+            let expected = vec!["!w".to_string(), "привіт".to_string()];
+            for content_type in CONTENT_TYPES {
+                let terms = post(
+                    content_type,
+                    "x=1&f=%21w+%D0%BF%D1%80%D0%B8%D0%B2%D1%96%D1%82",
+                );
+                assert_eq!(expected, terms.unwrap(), "{content_type}");
+            }
+        }
+
+        #[test]
+        fn missing_field() {
+            let parse_error = post(FORM_TYPE, "q=terms").unwrap_err();
+            assert!(
+                matches!(parse_error, QueryErr::BadRequest(ref err) if err == "Missing `f` field"),
+                "{:?}",
+                parse_error
+            );
+        }
+
+        #[test]
+        fn invalid_body() {
+            let request = format!("POST /search HTTP/1.1\r\nContent-Type: {FORM_TYPE}\r\n\r\n");
+            let mut request = parse(&request);
+            request.body = b"f=\xff".to_vec();
+            let parse_error = parse_query(&request, "q", "f").unwrap_err();
+            assert!(
+                matches!(parse_error, QueryErr::BadRequest(_)),
+                "{:?}",
+                parse_error
+            );
+        }
+
+        #[test]
+        fn unsupported_media_type() {
+            for content_type in ["multipart/form-data; boundary=x", "text/plain"] {
+                let parse_error = post(content_type, "f=terms").unwrap_err();
+                assert!(
+                    matches!(parse_error, QueryErr::UnsupportedMediaType(_)),
+                    "{:?}",
+                    parse_error
+                );
+            }
+            let parse_error = query("POST /search HTTP/1.1\r\n\r\nf=terms", "q").unwrap_err();
+            assert!(matches!(parse_error, QueryErr::UnsupportedMediaType(_)));
+        }
+    }
 }
@@ -1,4 +1,7 @@
 use std::collections::HashMap;
+use std::time::SystemTime;
+
+use crate::time::http_date;
 
 enum CharEncodeResult {
     Char(char),
@@ -7,12 +10,13 @@ enum CharEncodeResult {
 
 impl CharEncodeResult {
     fn _encode_byte(byte: u8) -> String {
-        format!("%{:X}", byte)
+        format!("%{:02X}", byte)
     }
 
     fn encode(ch: char) -> Self {
-        const SPECIAL: &str = "!#$&\"'()*+,/:;=?@[]";
-        if SPECIAL.contains(ch) {
+        const SPECIAL: &str = "!#$&\"'()*+,/:;=?@[] %";
+        // Control characters would end the header the URL is sent in
+        if SPECIAL.contains(ch) || ch.is_ascii_control() {
             CharEncodeResult::Str(CharEncodeResult::_encode_byte(ch as u8))
         } else {
             if ch.len_utf8() == 1 {
@@ -43,20 +47,119 @@ pub fn encode(text: &str) -> String {
     encoded
 }
 
+/// Standard status codes, see RFC 9110. Not every one of them is sent by banger.
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum StatusCode {
+    Ok,
+    Created,
+    Accepted,
+    NonAuthoritativeInformation,
+    NoContent,
+    ResetContent,
+    PartialContent,
+
+    MultipleChoices,
+    MovedPermanently,
+    Found,
     SeeOther,
+    NotModified,
+    TemporaryRedirect,
+    PermanentRedirect,
+
     BadRequest,
+    Unauthorized,
+    PaymentRequired,
+    Forbidden,
+    NotFound,
     MethodNotAllowed,
+    NotAcceptable,
+    ProxyAuthenticationRequired,
+    RequestTimeout,
+    Conflict,
+    Gone,
+    LengthRequired,
+    PreconditionFailed,
+    ContentTooLarge,
+    UriTooLong,
+    UnsupportedMediaType,
+    RangeNotSatisfiable,
+    ExpectationFailed,
+    MisdirectedRequest,
+    UnprocessableContent,
+    UpgradeRequired,
+    PreconditionRequired,
+    TooManyRequests,
+    RequestHeaderFieldsTooLarge,
+    UnavailableForLegalReasons,
+
+    InternalServerError,
+    NotImplemented,
+    BadGateway,
+    ServiceUnavailable,
+    GatewayTimeout,
+    HttpVersionNotSupported,
 }
 
 impl StatusCode {
-    fn msg(&self) -> String {
+    /// Code and reason phrase
+    fn parts(&self) -> (u16, &'static str) {
         match self {
-            StatusCode::SeeOther => "303 See Other",
-            StatusCode::BadRequest => "400 Bad Request",
-            StatusCode::MethodNotAllowed => "405 Method Not Allowed",
+            StatusCode::Ok => (200, "OK"),
+            StatusCode::Created => (201, "Created"),
+            StatusCode::Accepted => (202, "Accepted"),
+            StatusCode::NonAuthoritativeInformation => (203, "Non-Authoritative Information"),
+            StatusCode::NoContent => (204, "No Content"),
+            StatusCode::ResetContent => (205, "Reset Content"),
+            StatusCode::PartialContent => (206, "Partial Content"),
+            StatusCode::MultipleChoices => (300, "Multiple Choices"),
+            StatusCode::MovedPermanently => (301, "Moved Permanently"),
+            StatusCode::Found => (302, "Found"),
+            StatusCode::SeeOther => (303, "See Other"),
+            StatusCode::NotModified => (304, "Not Modified"),
+            StatusCode::TemporaryRedirect => (307, "Temporary Redirect"),
+            StatusCode::PermanentRedirect => (308, "Permanent Redirect"),
+            StatusCode::BadRequest => (400, "Bad Request"),
+            StatusCode::Unauthorized => (401, "Unauthorized"),
+            StatusCode::PaymentRequired => (402, "Payment Required"),
+            StatusCode::Forbidden => (403, "Forbidden"),
+            StatusCode::NotFound => (404, "Not Found"),
+            StatusCode::MethodNotAllowed => (405, "Method Not Allowed"),
+            StatusCode::NotAcceptable => (406, "Not Acceptable"),
+            StatusCode::ProxyAuthenticationRequired => (407, "Proxy Authentication Required"),
+            StatusCode::RequestTimeout => (408, "Request Timeout"),
+            StatusCode::Conflict => (409, "Conflict"),
+            StatusCode::Gone => (410, "Gone"),
+            StatusCode::LengthRequired => (411, "Length Required"),
+            StatusCode::PreconditionFailed => (412, "Precondition Failed"),
+            StatusCode::ContentTooLarge => (413, "Content Too Large"),
+            StatusCode::UriTooLong => (414, "URI Too Long"),
+            StatusCode::UnsupportedMediaType => (415, "Unsupported Media Type"),
+            StatusCode::RangeNotSatisfiable => (416, "Range Not Satisfiable"),
+            StatusCode::ExpectationFailed => (417, "Expectation Failed"),
+            StatusCode::MisdirectedRequest => (421, "Misdirected Request"),
+            StatusCode::UnprocessableContent => (422, "Unprocessable Content"),
+            StatusCode::UpgradeRequired => (426, "Upgrade Required"),
+            StatusCode::PreconditionRequired => (428, "Precondition Required"),
+            StatusCode::TooManyRequests => (429, "Too Many Requests"),
+            StatusCode::RequestHeaderFieldsTooLarge => (431, "Request Header Fields Too Large"),
+            StatusCode::UnavailableForLegalReasons => (451, "Unavailable For Legal Reasons"),
+            StatusCode::InternalServerError => (500, "Internal Server Error"),
+            StatusCode::NotImplemented => (501, "Not Implemented"),
+            StatusCode::BadGateway => (502, "Bad Gateway"),
+            StatusCode::ServiceUnavailable => (503, "Service Unavailable"),
+            StatusCode::GatewayTimeout => (504, "Gateway Timeout"),
+            StatusCode::HttpVersionNotSupported => (505, "HTTP Version Not Supported"),
         }
-        .to_string()
+    }
+
+    pub fn code(&self) -> u16 {
+        self.parts().0
+    }
+
+    fn msg(&self) -> String {
+        let (code, reason) = self.parts();
+        format!("{code} {reason}")
     }
 }
 
@@ -65,15 +168,18 @@ pub struct Response {
     status: StatusCode,
     headers: HashMap<String, String>,
     body: Option<String>,
+    /// Response to HEAD request: headers describe the body, but it is not sent
+    head: bool,
 }
 
 impl Response {
     pub fn new(proto: &str, status: StatusCode) -> Self {
         Response {
             proto: proto.to_string(),
-            status: status,
+            status,
             headers: HashMap::new(),
             body: None,
+            head: false,
         }
     }
 
@@ -82,21 +188,70 @@ impl Response {
         self
     }
 
+    /// Tells the client that the connection is closed after this response
+    pub fn close(&mut self) -> &mut Self {
+        self.header("Connection", "close")
+    }
+
+    /// Adds `Date` and `Server` headers
+    pub fn stamp(&mut self, now: SystemTime) -> &mut Self {
+        const SERVER: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+        self.header("Date", &http_date(now))
+            .header("Server", SERVER)
+    }
+
     pub fn body(&mut self, value: &str) -> &mut Self {
         self.body = Some(value.to_string());
         self
     }
 
+    /// Sets an HTML document as the body
+    pub fn html(&mut self, document: &str) -> &mut Self {
+        self.header("Content-Type", "text/html; charset=utf-8")
+            .body(document)
+    }
+
+    /// Makes this a response to HEAD request, so the body is omitted
+    pub fn head(&mut self) -> &mut Self {
+        self.head = true;
+        self
+    }
+
+    pub fn status(&self) -> &StatusCode {
+        &self.status
+    }
+
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    /// Number of body bytes that are sent
+    pub fn sent_body_len(&self) -> usize {
+        match &self.body {
+            Some(body) if !self.head => body.len(),
+            _ => 0,
+        }
+    }
+
     pub fn make(&self) -> String {
         const NEWLINE: &str = "\r\n";
         let body = match &self.body {
-            Some(value) => &value,
+            Some(value) => value,
             None => "",
         };
         let mut headers_block = String::new();
         for (name, value) in self.headers.iter() {
+            // Line breaks would end the header early and smuggle the rest into the message
+            if value.contains(['\r', '\n']) {
+                continue;
+            }
             headers_block.push_str(&format!("{name}: {value}{NEWLINE}"));
         }
+        // Lets the client find the end of the message on a persistent connection
+        if !matches!(self.status, StatusCode::NoContent) {
+            headers_block.push_str(&format!("Content-Length: {}{NEWLINE}", body.len()));
+        }
+        let body = if self.head { "" } else { body };
         format!(
             "{} {}{}{}{}{}",
             self.proto,
@@ -112,6 +267,7 @@ impl Response {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
 
     #[test]
     fn encode_success() {
@@ -119,6 +275,19 @@ mod tests {
             "abcd%D0%BF%D1%80%D0%B8%D0%B2%D1%96%D1%82%F0%9F%98%83%21%23%24%26%22%27%28%29%2A%2B%2C%2F%3A%3B%3D%3F%40%5B%5D".to_string(),
             encode("abcdпривіт😃!#$&\"'()*+,/:;=?@[]")
         );
+        assert_eq!("a%0D%0Ab%00%09%7F%20%25", encode("a\r\nb\0\t\x7f %"));
+    }
+
+    #[test]
+    fn response_header_line_break() {
+        let mut response = Response::new("PROTO", StatusCode::SeeOther);
+        response
+            .header("Location", "url\r\nX-Injected: 1")
+            .header("X-Other", "value");
+        let message = response.make();
+        assert!(!message.contains("X-Injected"), "{message:?}");
+        assert!(!message.contains("Location"), "{message:?}");
+        assert!(message.contains("X-Other: value\r\n"), "{message:?}");
     }
 
     #[test]
@@ -129,20 +298,95 @@ mod tests {
             .body("BODY")
             .make();
 
-        const TEMPLATE: &str =
-            "PROTO 303 See Other\r\nHeader{}: Value{}\r\nHeader{}: Value{}\r\n\r\nBODY";
+        const TEMPLATE: &str = "PROTO 303 See Other\r\nHeader{}: Value{}\r\nHeader{}: Value{}\r\nContent-Length: 4\r\n\r\nBODY";
         assert!(
             TEMPLATE.replacen("{}", "1", 2).replacen("{}", "2", 2) == response
                 || TEMPLATE.replacen("{}", "2", 2).replacen("{}", "1", 2) == response
         );
     }
 
+    #[test]
+    fn status_codes() {
+        assert_eq!("414 URI Too Long", StatusCode::UriTooLong.msg());
+        assert_eq!(500, StatusCode::InternalServerError.code());
+        assert_eq!(
+            "HTTP/1.1 505 HTTP Version Not Supported\r\nContent-Length: 0\r\n\r\n",
+            Response::new("HTTP/1.1", StatusCode::HttpVersionNotSupported).make()
+        );
+    }
+
+    #[test]
+    fn response_accessors() {
+        let mut response = Response::new("PROTO", StatusCode::SeeOther);
+        response.header("Location", "url").body("BODY");
+        assert_eq!(303, response.status().code());
+        assert_eq!(Some("url"), response.get_header("Location"));
+        assert_eq!(None, response.get_header("Other"));
+        assert_eq!(4, response.sent_body_len());
+        assert_eq!(0, response.head().sent_body_len());
+    }
+
+    #[test]
+    fn response_html() {
+        let mut response = Response::new("PROTO", StatusCode::Ok);
+        response.html("<p>hi</p>");
+        assert_eq!(
+            Some("text/html; charset=utf-8"),
+            response.get_header("Content-Type")
+        );
+        assert!(response.make().ends_with("\r\n\r\n<p>hi</p>"));
+    }
+
+    #[test]
+    fn response_head() {
+        let response = Response::new("PROTO", StatusCode::Ok)
+            .body("BODY")
+            .head()
+            .make();
+        assert_eq!("PROTO 200 OK\r\nContent-Length: 4\r\n\r\n", response);
+    }
+
+    #[test]
+    fn response_no_content() {
+        let response = Response::new("PROTO", StatusCode::NoContent).make();
+        assert_eq!("PROTO 204 No Content\r\n\r\n", response);
+    }
+
+    #[test]
+    fn response_stamp() {
+        let now = UNIX_EPOCH + Duration::from_secs(784111777);
+        let response = Response::new("PROTO", StatusCode::Ok).stamp(now).make();
+        assert!(response.contains("\r\nDate: Sun, 06 Nov 1994 08:49:37 GMT\r\n"));
+        assert!(response.contains("\r\nServer: banger_rs/"));
+    }
+
     #[test]
     fn response_bad_request() {
         let response = Response::new("PROTO", StatusCode::BadRequest)
             .body("Error description")
             .make();
-        assert_eq!("PROTO 400 Bad Request\r\n\r\nError description", response);
+        assert_eq!(
+            "PROTO 400 Bad Request\r\nContent-Length: 17\r\n\r\nError description",
+            response
+        );
+    }
+
+    #[test]
+    fn response_connection_close() {
+        let response = Response::new("PROTO", StatusCode::SeeOther).close().make();
+        assert_eq!(
+            "PROTO 303 See Other\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+            response
+        );
+    }
+
+    #[test]
+    fn response_service_unavailable() {
+        let response = Response::new("PROTO", StatusCode::ServiceUnavailable).make();
+        assert_eq!(
+            "PROTO 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+            response
+        );
     }
 
     #[test]
@@ -151,7 +395,7 @@ mod tests {
             .header("Allow", "Methods")
             .make();
         assert_eq!(
-            "PROTO 405 Method Not Allowed\r\nAllow: Methods\r\n\r\n",
+            "PROTO 405 Method Not Allowed\r\nAllow: Methods\r\nContent-Length: 0\r\n\r\n",
             response
         );
     }
@@ -1,211 +1,960 @@
-use std::io::{ErrorKind, Read, Write};
-use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::any::Any;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::IpAddr;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
 use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::bang_storage::BangStorage;
-use crate::request::{QueryErr, parse_query};
+use crate::access_log::{AccessLog, Entry};
+use crate::bang_storage::{BangStorage, RedirectMode};
+use crate::listener::{AnyConnection, Bound, Connection, Listener};
+use crate::log::{debug, error, info, trace, warn};
+use crate::metrics::{METRICS_PATH, METRICS_TYPE, Metrics};
+use crate::pages::{
+    BANGS_ALIAS, BANGS_PATH, OPENSEARCH_PATH, OPENSEARCH_TYPE, SUGGEST_PATH, SUGGEST_TYPE,
+    bangs_html, bangs_json, opensearch, refresh_html, suggestions,
+};
+use crate::pool::ThreadPool;
+use crate::rate_limit::RateLimiter;
+use crate::reader::{MessageReader, ReadErr, ReadLimits, ReadTimeouts};
+use crate::request::{FORM_TYPE, QueryErr, Request, SEARCH_PATH, parse_param, parse_query};
 use crate::response::{Response, StatusCode, encode};
+use crate::settings::Settings;
+use crate::signal::{ShutdownSignal, Wait};
 
+const PROTO: &str = "HTTP/1.1";
+
+/// Finds the URL to redirect the query to, with the status and the mode to redirect with.
+/// Fails if the default bang is not configured.
 fn process_query(
     storage: &BangStorage,
+    metrics: &Metrics,
     query: Vec<String>,
     encoder: &dyn Fn(&str) -> String,
-) -> String {
+) -> Result<(String, StatusCode, RedirectMode), String> {
     let mut bang_opt = None;
     let mut query_parts = vec![];
 
     for token in query.iter().filter(|line| !line.is_empty()) {
-        if token.len() == 0 {
-            continue;
-        }
-
         let mut token_it = token.chars();
-        let (first, rest) = (token_it.nth(0).unwrap(), token_it.as_str());
-        if bang_opt == None && first == '!' && storage.bangs.contains_key(rest) {
+        let (first, rest) = (token_it.next().unwrap(), token_it.as_str());
+        if bang_opt.is_none() && first == '!' && storage.bangs.contains_key(rest) {
+            storage.index.record_use(rest);
             bang_opt = Some(rest);
         } else {
+            if first == '!' && !rest.is_empty() && !storage.bangs.contains_key(rest) {
+                metrics.record_unknown_bang();
+            }
             query_parts.push(token.to_string());
         }
     }
 
     let bang = match bang_opt {
         Some(value) => value,
-        None => &storage.default,
+        None => {
+            metrics.record_default_fallback();
+            &storage.default
+        }
     };
+    let query_url = storage
+        .bangs
+        .get(bang)
+        .ok_or_else(|| format!("Default bang `{bang}` is not configured"))?;
+    metrics.record_redirect(storage, bang);
 
-    let encoded: Vec<String> = query_parts.iter().map(|part| encoder(&part)).collect();
-    storage.bangs[bang].replace("{}", &encoded.join("+"))
+    let encoded: Vec<String> = query_parts.iter().map(|part| encoder(part)).collect();
+    let url = query_url.replace("{}", &encoded.join("+"));
+    Ok((
+        url,
+        storage.redirect_status(bang),
+        storage.redirect_mode(bang),
+    ))
 }
 
-fn process_request(storage: &BangStorage, request: &str) -> String {
-    const PROTO: &str = "HTTP/1.1";
-    match parse_query(&request) {
-        Ok(query) => {
-            let response_url = process_query(&storage, query, &encode);
-            let mut response = Response::new(PROTO, StatusCode::SeeOther);
-            response.header("Location", &response_url);
+fn plain_response(status: StatusCode, text: &str) -> Response {
+    let mut response = Response::new(PROTO, status);
+    response.header("Content-Type", "text/plain");
+    response.body(text);
+    response
+}
+
+fn error_response(server: &Server, err: QueryErr) -> Response {
+    server.metrics.record_parse_error(&err);
+    match err {
+        QueryErr::BadRequest(err) | QueryErr::MalformedHeader(err) => {
+            plain_response(StatusCode::BadRequest, &err)
+        }
+        QueryErr::HeaderTooLarge(err) => {
+            plain_response(StatusCode::RequestHeaderFieldsTooLarge, &err)
+        }
+        QueryErr::UriTooLong(err) => plain_response(StatusCode::UriTooLong, &err),
+        QueryErr::ContentTooLarge(err) => plain_response(StatusCode::ContentTooLarge, &err),
+        QueryErr::UnsupportedMediaType(err) => {
+            let mut response = plain_response(StatusCode::UnsupportedMediaType, &err);
+            response.header("Accept-Post", FORM_TYPE);
+            response
+        }
+        QueryErr::MethodNotAllowed => {
+            let mut response = Response::new(PROTO, StatusCode::MethodNotAllowed);
+            response.header("Allow", &SEARCH_METHODS.join(", "));
             response
         }
-        Err(err) => match err {
-            QueryErr::BadRequest(err) => {
-                let mut response = Response::new(PROTO, StatusCode::BadRequest);
-                response.header("Content-Type", "text/plain");
-                response.body(&err);
-                response
-            }
-            QueryErr::MethodNotAllowed => {
-                let mut response = Response::new(PROTO, StatusCode::MethodNotAllowed);
-                response.header("Allow", "GET, HEAD");
-                response
-            }
-        },
     }
-    .make()
 }
 
-fn read_all(stream: &mut TcpStream) -> Result<Vec<u8>, String> {
-    const BUFFER_SIZE: usize = 512;
-    let mut buffer = [0; BUFFER_SIZE];
-    let mut result = Vec::new();
-    loop {
-        match stream.read(&mut buffer) {
-            Ok(read_count) => {
-                result.extend_from_slice(&buffer[..read_count]);
-                if read_count < BUFFER_SIZE {
-                    break;
+fn opensearch_response(server: &Server, _request: &Request) -> Response {
+    let settings = &server.settings;
+    let mut response = Response::new(PROTO, StatusCode::Ok);
+    response.header("Content-Type", OPENSEARCH_TYPE);
+    response.body(&opensearch(
+        &settings.short_name,
+        &server.base_url,
+        &settings.query_param,
+    ));
+    response
+}
+
+/// Completes the bang being typed as the last token of the query
+fn process_suggest(storage: &BangStorage, query: &[String]) -> Vec<String> {
+    const LIMIT: usize = 10;
+    let (last, previous) = match query.split_last() {
+        Some(v) => v,
+        None => return Vec::new(),
+    };
+    let prefix = match last.strip_prefix('!') {
+        Some(prefix) => prefix,
+        None => return Vec::new(),
+    };
+    storage
+        .index
+        .complete(prefix, LIMIT)
+        .into_iter()
+        .map(|alias| {
+            let mut completion = previous.to_vec();
+            completion.push(format!("!{alias}"));
+            completion.join(" ")
+        })
+        .collect()
+}
+
+fn suggest_response(server: &Server, request: &Request) -> Response {
+    let query = match parse_param(request, &server.settings.query_param) {
+        Ok(query) => query.unwrap_or_default(),
+        Err(err) => return error_response(server, err),
+    };
+    let completions = process_suggest(&server.storage, &query);
+    let mut response = Response::new(PROTO, StatusCode::Ok);
+    response.header("Content-Type", SUGGEST_TYPE);
+    response.body(&suggestions(&query.join(" "), &completions));
+    response
+}
+
+/// Checks whether the query asks for the list of bangs
+fn wants_bangs(storage: &BangStorage, query: &[String]) -> bool {
+    let reserved = format!("!{BANGS_ALIAS}");
+    !storage.bangs.contains_key(BANGS_ALIAS) && query.contains(&reserved)
+}
+
+fn search_response(server: &Server, request: &Request) -> Response {
+    let settings = &server.settings;
+    match parse_query(request, &settings.query_param, &settings.form_field) {
+        Ok(query) if wants_bangs(&server.storage, &query) => bangs_response(server, request),
+        Ok(query) => {
+            let (response_url, status, mode) =
+                match process_query(&server.storage, &server.metrics, query, &encode) {
+                    Ok(redirect) => redirect,
+                    Err(err) => {
+                        error!("Failed to process query", error = err);
+                        return plain_response(
+                            StatusCode::InternalServerError,
+                            "Internal server error",
+                        );
+                    }
+                };
+            let mut response = match mode {
+                RedirectMode::Location | RedirectMode::NoReferrer => {
+                    let mut response = Response::new(PROTO, status);
+                    response.header("Location", &response_url);
+                    response
                 }
-            }
-            Err(err) => {
-                if err.kind() == ErrorKind::WouldBlock {
-                    continue;
-                } else {
-                    return Err(err.to_string());
+                RedirectMode::Refresh => {
+                    let mut response = Response::new(PROTO, StatusCode::Ok);
+                    response.html(&refresh_html(
+                        &server.settings.short_name,
+                        &server.base_url,
+                        &response_url,
+                    ));
+                    response
                 }
+            };
+            if mode != RedirectMode::Location {
+                response.header("Referrer-Policy", "no-referrer");
             }
+            response
         }
+        Err(err) => error_response(server, err),
     }
-    Ok(result)
 }
 
-fn shutdown(stream: &mut TcpStream) -> () {
-    match stream.shutdown(Shutdown::Both) {
-        Ok(_) => (),
-        Err(err) => {
-            eprintln!("Failed to shutdown stream: {err}")
+fn health_response(_server: &Server, _request: &Request) -> Response {
+    let mut response = Response::new(PROTO, StatusCode::Ok);
+    response.header("Content-Type", "text/plain");
+    response.body("OK\n");
+    response
+}
+
+fn bangs_response(server: &Server, _request: &Request) -> Response {
+    let mut response = Response::new(PROTO, StatusCode::Ok);
+    response.html(&bangs_html(
+        &server.storage,
+        &server.settings.short_name,
+        &server.base_url,
+    ));
+    response
+}
+
+fn bangs_json_response(server: &Server, _request: &Request) -> Response {
+    let mut response = Response::new(PROTO, StatusCode::Ok);
+    response.header("Content-Type", "application/json");
+    response.body(&bangs_json(&server.storage));
+    response
+}
+
+fn favicon_response(_server: &Server, _request: &Request) -> Response {
+    // There is no icon, but browsers should not ask again for a while
+    let mut response = Response::new(PROTO, StatusCode::NoContent);
+    response.header("Cache-Control", "max-age=604800");
+    response
+}
+
+fn robots_response(_server: &Server, _request: &Request) -> Response {
+    // Redirects are not worth crawling
+    let mut response = Response::new(PROTO, StatusCode::Ok);
+    response.header("Content-Type", "text/plain");
+    response.body("User-agent: *\nDisallow: /\n");
+    response
+}
+
+fn metrics_response(server: &Server, _request: &Request) -> Response {
+    let mut response = Response::new(PROTO, StatusCode::Ok);
+    response.header("Content-Type", METRICS_TYPE);
+    response.body(&server.metrics.render());
+    response
+}
+
+fn too_many_requests_response(retry_after: Duration) -> Response {
+    // Retry-After takes whole seconds, rounding down would invite another rejection
+    let seconds = retry_after
+        .as_secs()
+        .saturating_add(u64::from(retry_after.subsec_nanos() > 0));
+    let mut response = Response::new(PROTO, StatusCode::TooManyRequests);
+    response
+        .header("Content-Type", "text/plain")
+        .header("Retry-After", &seconds.to_string())
+        .body("Too many requests");
+    response
+}
+
+fn request_timeout_response() -> Response {
+    let mut response = Response::new(PROTO, StatusCode::RequestTimeout);
+    response.header("Content-Type", "text/plain");
+    response.body("Request took too long to send");
+    response
+}
+
+fn not_found_response() -> Response {
+    let mut response = Response::new(PROTO, StatusCode::NotFound);
+    response.header("Content-Type", "text/plain");
+    response.body("Not found");
+    response
+}
+
+type Handler = fn(&Server, &Request) -> Response;
+
+struct Route {
+    /// Path without the leading slash
+    path: &'static str,
+    methods: &'static [&'static str],
+    handler: Handler,
+}
+
+const READ_METHODS: &[&str] = &["GET", "HEAD"];
+/// Browsers may submit search forms with `POST`
+const SEARCH_METHODS: &[&str] = &["GET", "HEAD", "POST"];
+
+const ROUTES: [Route; 9] = [
+    Route {
+        path: SEARCH_PATH,
+        methods: SEARCH_METHODS,
+        handler: search_response,
+    },
+    Route {
+        path: SUGGEST_PATH,
+        methods: READ_METHODS,
+        handler: suggest_response,
+    },
+    Route {
+        path: OPENSEARCH_PATH,
+        methods: READ_METHODS,
+        handler: opensearch_response,
+    },
+    Route {
+        path: "health",
+        methods: READ_METHODS,
+        handler: health_response,
+    },
+    Route {
+        path: BANGS_PATH,
+        methods: READ_METHODS,
+        handler: bangs_response,
+    },
+    Route {
+        path: "bangs.json",
+        methods: READ_METHODS,
+        handler: bangs_json_response,
+    },
+    Route {
+        path: "favicon.ico",
+        methods: READ_METHODS,
+        handler: favicon_response,
+    },
+    Route {
+        path: "robots.txt",
+        methods: READ_METHODS,
+        handler: robots_response,
+    },
+    Route {
+        path: METRICS_PATH,
+        methods: READ_METHODS,
+        handler: metrics_response,
+    },
+];
+
+/// Routes of the admin address, when metrics are served apart from searches
+const ADMIN_ROUTES: [Route; 2] = [
+    Route {
+        path: METRICS_PATH,
+        methods: READ_METHODS,
+        handler: metrics_response,
+    },
+    Route {
+        path: "health",
+        methods: READ_METHODS,
+        handler: health_response,
+    },
+];
+
+/// Bare path holds search terms, e.g. `/!w+filter+bubble`
+const LEGACY_SEARCH: Route = Route {
+    path: "",
+    methods: READ_METHODS,
+    handler: search_response,
+};
+
+fn find_route(path: &str) -> Option<&'static Route> {
+    match ROUTES.iter().find(|route| route.path == path) {
+        Some(route) => Some(route),
+        // Search terms are percent-encoded, so they never contain a slash
+        None if !path.contains('/') => Some(&LEGACY_SEARCH),
+        None => None,
+    }
+}
+
+/// Which endpoints a listener serves
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Public,
+    /// Metrics and health only
+    Admin,
+}
+
+fn role_route(server: &Server, path: &str, role: Role) -> Option<&'static Route> {
+    match role {
+        // Metrics stay private once they have an address of their own
+        Role::Public => find_route(path).filter(|route| {
+            route.path != METRICS_PATH || server.settings.metrics_address.is_none()
+        }),
+        Role::Admin => ADMIN_ROUTES.iter().find(|route| route.path == path),
+    }
+}
+
+/// Names the route of a request for latency metrics
+fn route_label(server: &Server, request: Option<&Request>, role: Role) -> &'static str {
+    let request = match request {
+        Some(request) => request,
+        None => return "invalid",
+    };
+    match role_route(server, request.path(), role) {
+        Some(route) if route.path.is_empty() => "legacy_search",
+        Some(route) => route.path,
+        None => "not_found",
+    }
+}
+
+fn route_request(server: &Server, request: &Request, role: Role) -> Response {
+    match role_route(server, request.path(), role) {
+        Some(route) if route.methods.contains(&request.method.as_str()) => {
+            (route.handler)(server, request)
         }
+        Some(route) => {
+            let mut response = Response::new(PROTO, StatusCode::MethodNotAllowed);
+            response.header("Allow", &route.methods.join(", "));
+            response
+        }
+        None => not_found_response(),
     }
 }
 
-fn serve_one(storage: Arc<BangStorage>, mut stream_arc: Arc<TcpStream>) -> () {
-    let mut stream = Arc::<TcpStream>::get_mut(&mut stream_arc).unwrap();
-    // Read
-    let request_bytes = match read_all(&mut stream) {
-        Ok(v) => v,
-        Err(err) => {
-            eprintln!("Failed to read from stream: {err}");
-            shutdown(&mut stream);
-            return;
+/// Gets the message a panic was started with
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    match (panic.downcast_ref::<&str>(), panic.downcast_ref::<String>()) {
+        (Some(message), _) => message,
+        (None, Some(message)) => message,
+        (None, None) => "unknown panic",
+    }
+}
+
+/// Logs panics as error events instead of the default message
+fn install_panic_hook() {
+    panic::set_hook(Box::new(|info| {
+        let thread = thread::current();
+        let location = match info.location() {
+            Some(location) => format!("{}:{}", location.file(), location.line()),
+            None => "-".to_string(),
+        };
+        error!(
+            "Panicked",
+            thread = thread.name().unwrap_or("-"),
+            location = location,
+            panic = panic_message(info.payload())
+        );
+    }));
+}
+
+/// Answers with 500 if `handle` panics, so one request does not take down the worker
+fn catch_panic(request: &Request, handle: impl FnOnce() -> Response) -> Response {
+    // Handlers only read shared state, so it is still consistent after a panic
+    match panic::catch_unwind(AssertUnwindSafe(handle)) {
+        Ok(response) => response,
+        Err(panic) => {
+            error!(
+                "Request handler panicked",
+                method = request.method,
+                target = request.target,
+                panic = panic_message(&*panic)
+            );
+            plain_response(StatusCode::InternalServerError, "Internal server error")
         }
-    };
+    }
+}
 
-    // Process
-    let request = match String::from_utf8(request_bytes) {
-        Ok(v) => v,
-        Err(_) => "".to_string(),
-    };
-    eprintln!("Got request: {:?}", request);
-    let response = process_request(&storage, &request);
-    eprintln!("Made response: {:?}", response);
-    let response_bytes = response.as_bytes();
+fn process_request(server: &Server, request: &Request, role: Role) -> Response {
+    let mut response = catch_panic(request, || route_request(server, request, role));
+    if request.method == "HEAD" {
+        response.head();
+    }
+    response
+}
 
-    // Write
-    match stream.write(response_bytes) {
+fn shutdown<S: Connection>(stream: &mut S) {
+    match stream.shutdown() {
         Ok(_) => (),
         Err(err) => {
-            eprintln!("Failed to write to stream: {err}");
-            shutdown(&mut stream);
-            return;
+            debug!("Failed to shutdown stream", error = err)
+        }
+    }
+}
+
+/// State shared by the accept loop and workers
+struct Server {
+    storage: BangStorage,
+    settings: Settings,
+    signal: ShutdownSignal,
+    /// URL the server is reachable at, without trailing slash
+    base_url: String,
+    access_log: AccessLog,
+    metrics: Metrics,
+    rate_limiter: Option<RateLimiter>,
+}
+
+/// Takes a token from the bucket of the client, gives the time until the next one
+/// if there are none left. Admin and Unix socket clients are not limited.
+fn admit(server: &Server, peer: Option<IpAddr>, role: Role) -> Result<(), Duration> {
+    match (&server.rate_limiter, peer, role) {
+        (Some(limiter), Some(ip), Role::Public) => limiter.check(ip, Instant::now()),
+        _ => Ok(()),
+    }
+}
+
+fn serve_one<S: Connection>(server: &Server, stream: S, role: Role) {
+    let settings = &server.settings;
+    let peer = stream.peer_ip();
+    let peer_field = match peer {
+        Some(ip) => ip.to_string(),
+        None => "-".to_string(),
+    };
+    debug!("Serving connection", peer = peer_field);
+    server.metrics.connection_opened();
+    if let Err(err) = stream.set_write_timeout(Some(settings.write_timeout)) {
+        warn!(
+            "Failed to set stream timeout",
+            peer = peer_field,
+            error = err
+        );
+    }
+    let deadline = Instant::now() + settings.connection_timeout;
+    let timeouts = ReadTimeouts {
+        idle: settings.idle_timeout,
+        read: settings.read_timeout,
+        header: settings.header_timeout,
+        body: settings.body_timeout,
+    };
+    let limits = ReadLimits {
+        request_line: settings.max_request_line,
+        header: settings.max_header_size,
+        header_lines: settings.max_header_lines,
+        body: settings.max_body_size,
+    };
+    let mut reader = MessageReader::new(stream, timeouts, limits);
+    reader.set_deadline(deadline);
+    loop {
+        // Wait for the next request, unless it is already buffered.
+        // A request that already arrived is served even after shutdown is requested.
+        if !reader.has_buffered() && !reader.get_mut().has_pending() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                break;
+            }
+            let fd = reader.get_mut().as_raw_fd();
+            match server
+                .signal
+                .wait(fd, Some(settings.idle_timeout.min(left)))
+            {
+                Ok(Wait::Ready) => (),
+                Ok(Wait::Shutdown) | Ok(Wait::TimedOut) => break,
+                Err(err) => {
+                    error!("Failed to wait for stream", peer = peer_field, error = err);
+                    break;
+                }
+            }
+        }
+
+        // Read
+        let (received, started) = (SystemTime::now(), Instant::now());
+        let parsed = match reader.read_message() {
+            Ok(Some(request_bytes)) => Request::parse(&request_bytes),
+            Ok(None) => break,
+            Err(ReadErr::BadRequest(err)) => Err(QueryErr::BadRequest(err)),
+            Err(ReadErr::UriTooLong(err)) => Err(QueryErr::UriTooLong(err)),
+            Err(ReadErr::HeaderTooLarge(err)) => Err(QueryErr::HeaderTooLarge(err)),
+            Err(ReadErr::BodyTooLarge(err)) => Err(QueryErr::ContentTooLarge(err)),
+            Err(ReadErr::TimedOut) => {
+                debug!("Timed out reading from stream", peer = peer_field);
+                send_last(
+                    server,
+                    reader.get_mut(),
+                    request_timeout_response(),
+                    started,
+                );
+                break;
+            }
+            Err(ReadErr::Io(err)) => {
+                warn!("Failed to read from stream", peer = peer_field, error = err);
+                break;
+            }
+        };
+
+        // Process
+        let admitted = admit(server, peer, role);
+        let (request, mut response) = match parsed {
+            Ok(request) => {
+                trace!(
+                    "Parsed request",
+                    peer = peer_field,
+                    method = request.method,
+                    target = request.target,
+                    version = request.version,
+                    headers = request.headers.len(),
+                    body_bytes = request.body.len()
+                );
+                let response = match admitted {
+                    Ok(()) => process_request(server, &request, role),
+                    Err(retry_after) => {
+                        debug!("Rate limited request", peer = peer_field);
+                        let mut response = too_many_requests_response(retry_after);
+                        if request.method == "HEAD" {
+                            response.head();
+                        }
+                        response
+                    }
+                };
+                (Some(request), response)
+            }
+            Err(err) => (None, error_response(server, err)),
+        };
+        // Limited clients reconnect, so they do not hold a worker meanwhile
+        let keep_alive = request.as_ref().is_some_and(Request::keep_alive)
+            && admitted.is_ok()
+            && Instant::now() < deadline
+            && !server.signal.is_triggered();
+        if !keep_alive {
+            response.close();
+        }
+        let message = response.stamp(SystemTime::now()).make();
+
+        // Write
+        let stream = reader.get_mut();
+        let written = stream
+            .write_all(message.as_bytes())
+            .and_then(|_| stream.flush());
+        let latency = started.elapsed();
+        server.access_log.write(&Entry {
+            peer,
+            time: received,
+            request: request.as_ref(),
+            status: response.status().code(),
+            bytes: response.sent_body_len(),
+            location: response.get_header("Location"),
+            latency,
+        });
+        server.metrics.record_response(response.status().code());
+        server
+            .metrics
+            .record_latency(route_label(server, request.as_ref(), role), latency);
+        if let Err(err) = written {
+            warn!("Failed to write to stream", peer = peer_field, error = err);
+            break;
+        }
+
+        if !keep_alive {
+            break;
         }
     }
 
     // Close
+    shutdown(reader.get_mut());
+    server.metrics.connection_closed();
+}
+
+/// Sends the last response of a connection, when there is no request to answer
+fn send_last<S: Connection>(
+    server: &Server,
+    stream: &mut S,
+    mut response: Response,
+    started: Instant,
+) {
+    response.close().stamp(SystemTime::now());
+    if let Err(err) = stream
+        .write_all(response.make().as_bytes())
+        .and_then(|_| stream.flush())
+    {
+        warn!("Failed to write to stream", error = err);
+    }
+    server.access_log.write(&Entry {
+        peer: stream.peer_ip(),
+        time: SystemTime::now(),
+        request: None,
+        status: response.status().code(),
+        bytes: response.sent_body_len(),
+        location: None,
+        latency: started.elapsed(),
+    });
+    server.metrics.record_response(response.status().code());
+}
+
+/// Tells a client that all workers are busy and closes the connection.
+/// Runs on the accept loop, so it never waits for the client: the response is written
+/// without blocking, and connections that need a TLS handshake are closed without one.
+fn reject<S: Connection>(server: &Server, mut stream: S) {
+    let started = Instant::now();
+    if let Err(err) = stream.set_nonblocking(true) {
+        warn!("Failed to make stream non-blocking", error = err);
+    }
+    if stream.is_handshaking() {
+        debug!("Closing connection before handshake");
+    } else {
+        let mut response = Response::new(PROTO, StatusCode::ServiceUnavailable);
+        response
+            .header("Content-Type", "text/plain")
+            .header("Retry-After", "1")
+            .body("Server is busy");
+        send_last(server, &mut stream, response, started);
+    }
     shutdown(&mut stream);
 }
 
-pub fn serve(storage: BangStorage, address: SocketAddr) -> Result<(), String> {
-    let storage_arc = Arc::new(storage);
-    let listener = TcpListener::bind(address).map_err(|err| format!("{err}"))?;
-    for stream_res in listener.incoming() {
-        let stream_arc = match stream_res {
-            Ok(stream) => Arc::new(stream),
+/// Connections that one listener took
+#[derive(Default)]
+struct ListenerStats {
+    accepted: usize,
+    rejected: usize,
+}
+
+/// Accepts connections from one listener until shutdown is requested
+fn accept_loop<L: Listener>(
+    server: &Server,
+    listener: L,
+    role: Role,
+    pool: &ThreadPool<(AnyConnection, Role)>,
+    stats: &mut ListenerStats,
+) -> Result<(), String> {
+    listener
+        .set_nonblocking(true)
+        .map_err(|err| format!("Failed to make listener non-blocking: {err}"))?;
+    loop {
+        match server.signal.wait(listener.as_raw_fd(), None) {
+            // Pending connections are left to the backlog once shutdown is requested
+            Ok(Wait::Ready) if !server.signal.is_triggered() => (),
+            Ok(_) => return Ok(()),
+            Err(err) => return Err(format!("Failed to wait for connections: {err}")),
+        }
+        let stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => continue,
             Err(err) => {
-                eprintln!("Failed to accept connection: {err}");
+                error!("Failed to accept connection", error = err);
                 continue;
             }
         };
-        match stream_arc.set_nonblocking(true) {
-            Ok(_) => (),
-            Err(err) => {
-                return Err(format!("Failed to make socket non-blocking: {err}"));
+        if let Err(err) = stream.set_nonblocking(false) {
+            error!("Failed to make stream blocking", error = err);
+            continue;
+        }
+
+        stats.accepted += 1;
+        if let Err((stream, _role)) = pool.try_execute((Box::new(stream), role)) {
+            warn!("Connection queue is full, rejecting connection");
+            stats.rejected += 1;
+            reject(server, stream);
+        }
+    }
+}
+
+/// Runs the accept loop of a listener and removes its socket file afterwards.
+/// A failure is logged right away, other listeners keep serving.
+fn serve_listener(
+    server: &Server,
+    listener: Bound,
+    role: Role,
+    pool: &ThreadPool<(AnyConnection, Role)>,
+) -> (ListenerStats, Result<(), String>) {
+    let mut stats = ListenerStats::default();
+    let name = listener.describe();
+    let result = match listener {
+        Bound::Tcp(listener) => accept_loop(server, listener, role, pool, &mut stats),
+        Bound::Unix(listener, path) => {
+            let result = accept_loop(server, listener, role, pool, &mut stats);
+            if let Some(path) = path
+                && let Err(err) = fs::remove_file(&path)
+            {
+                warn!(
+                    "Failed to remove socket",
+                    path = path.display(),
+                    error = err
+                );
             }
+            result
         }
+        #[cfg(feature = "tls")]
+        Bound::Tls(listener) => accept_loop(server, listener, role, pool, &mut stats),
+    };
+    if let Err(err) = &result {
+        error!("Listener failed", listener = name, error = err);
+    }
+    (stats, result)
+}
 
-        let storage_cl = storage_arc.clone();
-        let stream_cl = stream_arc.clone();
-        let _ = thread::spawn(|| serve_one(storage_cl, stream_cl));
+/// Accepts connections on every listener until shutdown is requested, then drains workers
+fn run(server: Arc<Server>, listeners: Vec<(Bound, Role)>) -> Result<(), String> {
+    let server_cl = server.clone();
+    let pool = ThreadPool::new(
+        server.settings.workers,
+        server.settings.queue_depth,
+        Arc::new(move |(stream, role)| serve_one(&server_cl, stream, role)),
+    )?;
+
+    let (mut accepted, mut rejected, mut failed) = (0, 0, Vec::new());
+    thread::scope(|scope| {
+        let handles: Vec<_> = listeners
+            .into_iter()
+            .map(|(listener, role)| {
+                let name = listener.describe();
+                let (server, pool) = (&server, &pool);
+                let handle = scope.spawn(move || serve_listener(server, listener, role, pool));
+                (name, handle)
+            })
+            .collect();
+        // Failures are logged by the listeners themselves, panics by the panic hook
+        for (name, handle) in handles {
+            let (stats, result) = match handle.join() {
+                Ok(v) => v,
+                Err(_panic) => (
+                    ListenerStats::default(),
+                    Err("Accept loop panicked".to_string()),
+                ),
+            };
+            accepted += stats.accepted;
+            rejected += stats.rejected;
+            if result.is_err() {
+                failed.push(name);
+            }
+        }
+    });
+
+    let grace_period = server.settings.grace_period;
+    info!(
+        "Shutting down, waiting for workers",
+        grace_period = format!("{grace_period:?}")
+    );
+    let busy = pool.join(grace_period);
+    info!(
+        "Shut down",
+        accepted = accepted,
+        rejected = rejected,
+        busy_workers = busy
+    );
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Listeners failed: {}", failed.join(", ")))
     }
+}
 
-    Ok(())
+/// Serves on all listeners, the first one gives the default base URL.
+/// `admin` listener serves metrics apart from searches.
+pub fn serve(
+    storage: BangStorage,
+    settings: Settings,
+    listeners: Vec<Bound>,
+    admin: Option<Bound>,
+) -> Result<(), String> {
+    install_panic_hook();
+    let signal = ShutdownSignal::new()
+        .and_then(|signal| signal.register().map(|_| signal))
+        .map_err(|err| format!("Failed to set up signal handling: {err}"))?;
+    let base_url = match (&settings.base_url, listeners.first()) {
+        (Some(url), _) => url.clone(),
+        (None, Some(listener @ Bound::Tcp(_))) => format!("http://{}", listener.describe()),
+        #[cfg(feature = "tls")]
+        (None, Some(Bound::Tls(listener))) => match listener.local_addr() {
+            Ok(address) => format!("https://{address}"),
+            Err(_err) => "https://localhost".to_string(),
+        },
+        // Clients of a Unix socket tell the public URL only with `base_url`
+        (None, _) => "http://localhost".to_string(),
+    };
+    let access_log = AccessLog::open(settings.access_log.as_deref(), settings.access_log_format)?;
+    access_log
+        .register()
+        .map_err(|err| format!("Failed to set up signal handling: {err}"))?;
+    let metrics = Metrics::new(&storage);
+    let rate_limiter = settings.rate_limit.clone().map(RateLimiter::new);
+    let server = Arc::new(Server {
+        storage,
+        settings,
+        signal,
+        base_url,
+        access_log,
+        metrics,
+        rate_limiter,
+    });
+    let listeners = listeners
+        .into_iter()
+        .map(|listener| (listener, Role::Public))
+        .chain(admin.map(|listener| (listener, Role::Admin)))
+        .collect();
+    run(server, listeners)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::collections::HashMap;
+    use crate::access_log::LogFormat;
+    use crate::bang_storage::Bang;
+    use crate::rate_limit::RateLimitSettings;
+    use std::env;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::process;
 
     #[test]
     fn process_query_success() {
-        let bangs = BangStorage {
-            bangs: HashMap::from([("alias".to_string(), "url?q={}".to_string())]),
-            default: "alias".to_string(),
-        };
+        let bangs = BangStorage::new(
+            vec![Bang {
+                aliases: vec!["alias".to_string()],
+                query: "url?q={}".to_string(),
+                redirect_status: Some(StatusCode::TemporaryRedirect),
+                redirect_mode: None,
+            }],
+            "alias".to_string(),
+        );
         let query = vec![
             "inserted".to_string(),
             "!alias".to_string(),
             "values".to_string(),
         ];
         assert_eq!(
-            "url?q=inserted+values",
-            process_query(&bangs, query, &encode)
+            (
+                "url?q=inserted+values".to_string(),
+                StatusCode::TemporaryRedirect,
+                RedirectMode::Location
+            ),
+            process_query(&bangs, &Metrics::new(&bangs), query, &encode).unwrap()
         );
     }
 
     #[test]
     fn process_query_default() {
-        let bangs = BangStorage {
-            bangs: HashMap::from([
-                ("alias".to_string(), "url?q={}".to_string()),
-                ("default".to_string(), "default?q={}".to_string()),
-            ]),
-            default: "default".to_string(),
-        };
+        let bangs = BangStorage::new(
+            vec![
+                Bang {
+                    aliases: vec!["alias".to_string()],
+                    query: "url?q={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+                Bang {
+                    aliases: vec!["default".to_string()],
+                    query: "default?q={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+            ],
+            "default".to_string(),
+        );
         let query = vec!["inserted".to_string(), "values".to_string()];
         assert_eq!(
-            "default?q=inserted+values",
-            process_query(&bangs, query, &encode)
+            (
+                "default?q=inserted+values".to_string(),
+                StatusCode::SeeOther,
+                RedirectMode::Location
+            ),
+            process_query(&bangs, &Metrics::new(&bangs), query, &encode).unwrap()
         );
     }
 
     #[test]
     fn process_query_missing_bang() {
-        let bangs = BangStorage {
-            bangs: HashMap::from([
-                ("alias".to_string(), "url?q={}".to_string()),
-                ("default".to_string(), "default?q={}".to_string()),
-            ]),
-            default: "default".to_string(),
-        };
+        let bangs = BangStorage::new(
+            vec![
+                Bang {
+                    aliases: vec!["alias".to_string()],
+                    query: "url?q={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+                Bang {
+                    aliases: vec!["default".to_string()],
+                    query: "default?q={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+            ],
+            "default".to_string(),
+        );
         let query = vec![
             "inserted".to_string(),
             "!invalid".to_string(),
@@ -213,7 +962,617 @@ mod tests {
         ];
         assert_eq!(
             "default?q=inserted+%21invalid+values",
-            process_query(&bangs, query, &encode)
+            process_query(&bangs, &Metrics::new(&bangs), query, &encode)
+                .unwrap()
+                .0
+        );
+    }
+
+    #[test]
+    fn process_suggest_success() {
+        let bangs = BangStorage::new(
+            vec![
+                Bang {
+                    aliases: vec!["wiki".to_string(), "wikipedia".to_string()],
+                    query: "wiki?q={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+                Bang {
+                    aliases: vec!["ddg".to_string()],
+                    query: "ddg?q={}".to_string(),
+                    redirect_status: None,
+                    redirect_mode: None,
+                },
+            ],
+            "ddg".to_string(),
+        );
+        let query = vec!["filter".to_string(), "!wi".to_string()];
+        assert_eq!(
+            vec!["filter !wiki", "filter !wikipedia"],
+            process_suggest(&bangs, &query)
+        );
+
+        // Used bangs are suggested first
+        process_query(
+            &bangs,
+            &Metrics::new(&bangs),
+            vec!["!wikipedia".to_string()],
+            &encode,
+        )
+        .unwrap();
+        assert_eq!(
+            vec!["filter !wikipedia", "filter !wiki"],
+            process_suggest(&bangs, &query)
+        );
+    }
+
+    #[test]
+    fn process_suggest_no_bang() {
+        let bangs = BangStorage::new(
+            vec![Bang {
+                aliases: vec!["wiki".to_string()],
+                query: "wiki?q={}".to_string(),
+                redirect_status: None,
+                redirect_mode: None,
+            }],
+            "wiki".to_string(),
+        );
+        let query = vec!["!wi".to_string(), "filter".to_string()];
+        assert!(process_suggest(&bangs, &query).is_empty());
+        assert!(process_suggest(&bangs, &[]).is_empty());
+    }
+
+    fn test_server() -> Server {
+        let storage = BangStorage::new(
+            vec![Bang {
+                aliases: vec!["alias".to_string()],
+                query: "url?q={}".to_string(),
+                redirect_status: None,
+                redirect_mode: None,
+            }],
+            "alias".to_string(),
+        );
+        Server {
+            metrics: Metrics::new(&storage),
+            storage,
+            settings: Settings::default(),
+            signal: ShutdownSignal::new().unwrap(),
+            base_url: "http://banger.example".to_string(),
+            access_log: AccessLog::open(None, LogFormat::Common).unwrap(),
+            rate_limiter: None,
+        }
+    }
+
+    #[test]
+    fn process_request_routes() {
+        const REQUESTS: [(&str, &str); 9] = [
+            ("GET /search?q=a", "HTTP/1.1 303 See Other\r\n"),
+            ("GET /bangs", "HTTP/1.1 200 OK\r\n"),
+            ("GET /search?q=a+!bangs", "HTTP/1.1 200 OK\r\n"),
+            ("HEAD /a+b", "HTTP/1.1 303 See Other\r\n"),
+            ("HEAD /health", "HTTP/1.1 200 OK\r\n"),
+            ("GET /health", "HTTP/1.1 200 OK\r\n"),
+            ("GET /favicon.ico", "HTTP/1.1 204 No Content\r\n"),
+            ("GET /nested/path", "HTTP/1.1 404 Not Found\r\n"),
+            ("DELETE /search", "HTTP/1.1 405 Method Not Allowed\r\n"),
+        ];
+        let server = test_server();
+        for (start_line, expected) in REQUESTS {
+            let request = format!("{start_line} HTTP/1.1\r\n\r\n");
+            let response = process_request(
+                &server,
+                &Request::parse(request.as_bytes()).unwrap(),
+                Role::Public,
+            )
+            .make();
+            assert!(response.starts_with(expected), "{request:?}: {response:?}");
+            if start_line.starts_with("HEAD") {
+                assert!(response.ends_with("\r\n\r\n"), "{response:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn post_search() {
+        let server = test_server();
+        let post = |target: &str, content_type: &str, body: &str| {
+            let request = format!(
+                "POST {target} HTTP/1.1\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\r\n{body}",
+                body.len()
+            );
+            process_request(
+                &server,
+                &Request::parse(request.as_bytes()).unwrap(),
+                Role::Public,
+            )
+        };
+
+        let response = post("/search", FORM_TYPE, "q=a+b");
+        assert_eq!(StatusCode::SeeOther, *response.status());
+        assert_eq!(Some("url?q=a+b"), response.get_header("Location"));
+
+        let response = post("/search", "text/plain", "q=a+b");
+        assert_eq!(StatusCode::UnsupportedMediaType, *response.status());
+        assert_eq!(Some(FORM_TYPE), response.get_header("Accept-Post"));
+
+        // Only the search path takes forms
+        for target in ["/a+b", "/health"] {
+            let response = post(target, FORM_TYPE, "q=a+b");
+            assert_eq!(StatusCode::MethodNotAllowed, *response.status());
+            assert_eq!(Some("GET, HEAD"), response.get_header("Allow"));
+        }
+        let response = process_request(
+            &server,
+            &Request::parse(b"PUT /search HTTP/1.1\r\n\r\n").unwrap(),
+            Role::Public,
+        );
+        assert_eq!(Some("GET, HEAD, POST"), response.get_header("Allow"));
+    }
+
+    #[test]
+    fn header_injection() {
+        let server = test_server();
+        let request =
+            Request::parse(b"GET /search?q=a%0D%0AX-Injected%3A+1%0D%0A%0D%0A HTTP/1.1\r\n\r\n")
+                .unwrap();
+        let response = process_request(&server, &request, Role::Public);
+        assert_eq!(
+            Some("url?q=a%0D%0AX-Injected%3A+1%0D%0A%0D%0A"),
+            response.get_header("Location")
+        );
+        let message = response.make();
+        assert!(!message.contains("\r\nX-Injected"), "{message:?}");
+    }
+
+    #[test]
+    fn redirect_modes() {
+        let mut server = test_server();
+        let request = Request::parse(b"GET /search?q=a HTTP/1.1\r\n\r\n").unwrap();
+
+        let response = process_request(&server, &request, Role::Public);
+        assert_eq!(StatusCode::SeeOther, *response.status());
+        assert_eq!(None, response.get_header("Referrer-Policy"));
+
+        server.storage.redirect_mode = RedirectMode::NoReferrer;
+        let response = process_request(&server, &request, Role::Public);
+        assert_eq!(StatusCode::SeeOther, *response.status());
+        assert_eq!(Some("url?q=a"), response.get_header("Location"));
+        assert_eq!(Some("no-referrer"), response.get_header("Referrer-Policy"));
+
+        server.storage.redirect_mode = RedirectMode::Refresh;
+        let response = process_request(&server, &request, Role::Public);
+        assert_eq!(StatusCode::Ok, *response.status());
+        assert_eq!(None, response.get_header("Location"));
+        assert_eq!(Some("no-referrer"), response.get_header("Referrer-Policy"));
+        assert!(response.make().contains(r#"content="0; url=url?q=a""#));
+    }
+
+    #[test]
+    fn missing_default_bang() {
+        let mut server = test_server();
+        server.storage = BangStorage::new(vec![], "missing".to_string());
+        let request = Request::parse(b"GET /search?q=a HTTP/1.1\r\n\r\n").unwrap();
+        let response = process_request(&server, &request, Role::Public).make();
+        assert!(
+            response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn handler_panic() {
+        let request = Request::parse(b"GET /search?q=a HTTP/1.1\r\n\r\n").unwrap();
+        let response = catch_panic(&request, || panic!("handler failed")).make();
+        assert!(
+            response.starts_with("HTTP/1.1 500 Internal Server Error\r\n"),
+            "{response}"
+        );
+    }
+
+    #[test]
+    fn metrics_routes() {
+        let get = |server: &Server, target: &str, role: Role| {
+            let request = format!("GET {target} HTTP/1.1\r\n\r\n");
+            process_request(server, &Request::parse(request.as_bytes()).unwrap(), role).make()
+        };
+        let mut server = test_server();
+        get(&server, "/search?q=a+!unknown", Role::Public);
+        get(&server, "/search?q=!alias+a", Role::Public);
+        get(&server, "/search", Role::Public);
+        let metrics = get(&server, "/metrics", Role::Public);
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"), "{metrics}");
+        for line in [
+            "banger_redirects_total{bang=\"alias\"} 2\n",
+            "banger_default_bang_total 1\n",
+            "banger_unknown_bangs_total 1\n",
+            "banger_parse_errors_total{kind=\"bad_request\"} 1\n",
+        ] {
+            assert!(metrics.contains(line), "{line:?} in {metrics}");
+        }
+
+        // Admin address serves metrics, search addresses do not
+        server.settings.metrics_address = Some("127.0.0.1:0".parse().unwrap());
+        assert!(get(&server, "/metrics", Role::Public).starts_with("HTTP/1.1 404"));
+        assert!(get(&server, "/metrics", Role::Admin).starts_with("HTTP/1.1 200"));
+        assert!(get(&server, "/health", Role::Admin).starts_with("HTTP/1.1 200"));
+        assert!(get(&server, "/search?q=a", Role::Admin).starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn rate_limit() {
+        let mut server = test_server();
+        server.rate_limiter = Some(RateLimiter::new(RateLimitSettings {
+            rate: 0.1,
+            burst: 2,
+            ipv4_prefix: 32,
+            ipv6_prefix: 64,
+        }));
+        let server = Arc::new(server);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_cl = server.clone();
+        let handle =
+            thread::spawn(move || run(server_cl, vec![(Bound::Tcp(listener), Role::Public)]));
+
+        // Third request in a row is rejected and the connection is closed
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\n\r\n".repeat(3).as_slice())
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert_eq!(2, response.matches("HTTP/1.1 200 OK\r\n").count());
+        let rejected = &response[response.find("HTTP/1.1 429").expect(&response)..];
+        assert!(rejected.contains("Retry-After: 10\r\n"), "{rejected}");
+        assert!(rejected.contains("Connection: close\r\n"), "{rejected}");
+
+        // Admin address is not limited
+        assert_eq!(Ok(()), admit(&server, Some(address.ip()), Role::Admin));
+
+        server.signal.trigger();
+        assert_eq!(Ok(()), handle.join().unwrap());
+    }
+
+    #[test]
+    fn stalled_client() {
+        let mut server = test_server();
+        server.settings.workers = 1;
+        server.settings.queue_depth = 1;
+        server.settings.header_timeout = Duration::from_millis(300);
+        let server = Arc::new(server);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_cl = server.clone();
+        let handle =
+            thread::spawn(move || run(server_cl, vec![(Bound::Tcp(listener), Role::Public)]));
+
+        // Head is sent a byte at a time, each read is quick enough, but the head is never done
+        let mut stalled = TcpStream::connect(address).unwrap();
+        let mut writer = stalled.try_clone().unwrap();
+        let trickle = thread::spawn(move || {
+            for byte in b"GET /health HTTP/1.1\r\nX-Slow: ".repeat(10) {
+                if writer.write_all(&[byte]).is_err() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+        stalled
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut response = Vec::new();
+        let _ = stalled.read_to_end(&mut response);
+        assert!(
+            response.starts_with(b"HTTP/1.1 408 Request Timeout\r\n"),
+            "{}",
+            String::from_utf8_lossy(&response)
+        );
+
+        // The only worker is free for the next client
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        server.signal.trigger();
+        assert_eq!(Ok(()), handle.join().unwrap());
+        trickle.join().unwrap();
+    }
+
+    #[test]
+    fn wants_bangs_success() {
+        let query = vec!["a".to_string(), "!bangs".to_string()];
+        assert!(wants_bangs(&test_server().storage, &query));
+
+        // Configured alias takes over
+        let bangs = BangStorage::new(
+            vec![Bang {
+                aliases: vec!["bangs".to_string()],
+                query: "url?q={}".to_string(),
+                redirect_status: None,
+                redirect_mode: None,
+            }],
+            "bangs".to_string(),
         );
+        assert!(!wants_bangs(&bangs, &query));
+    }
+
+    #[test]
+    fn find_route_success() {
+        assert_eq!(SEARCH_PATH, find_route("search").unwrap().path);
+        assert_eq!("favicon.ico", find_route("favicon.ico").unwrap().path);
+        assert_eq!(LEGACY_SEARCH.path, find_route("!w+filter").unwrap().path);
+        assert_eq!(LEGACY_SEARCH.path, find_route("").unwrap().path);
+        assert!(find_route("nested/path").is_none());
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        let server = Arc::new(test_server());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_cl = server.clone();
+        let handle =
+            thread::spawn(move || run(server_cl, vec![(Bound::Tcp(listener), Role::Public)]));
+
+        // Connection is kept alive until shutdown is requested
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(b"GET /first HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = [0; 512];
+        let read_count = stream.read(&mut response).unwrap();
+        assert!(response[..read_count].starts_with(b"HTTP/1.1 303 See Other\r\n"));
+
+        server.signal.trigger();
+        assert_eq!(Ok(()), handle.join().unwrap());
+        assert_eq!(0, stream.read(&mut response).unwrap());
+        assert!(TcpStream::connect(address).is_err());
+    }
+
+    #[test]
+    fn busy() {
+        let mut server = test_server();
+        server.settings.workers = 1;
+        server.settings.queue_depth = 0;
+        let server = Arc::new(server);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_cl = server.clone();
+        let handle =
+            thread::spawn(move || run(server_cl, vec![(Bound::Tcp(listener), Role::Public)]));
+
+        // The only worker waits on a kept-alive connection
+        let mut kept = TcpStream::connect(address).unwrap();
+        kept.write_all(b"GET /health HTTP/1.1\r\n\r\n").unwrap();
+        let read_count = kept.read(&mut [0; 512]).unwrap();
+        assert!(read_count > 0);
+
+        let mut rejected = TcpStream::connect(address).unwrap();
+        let mut response = String::new();
+        rejected.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("Retry-After: 1\r\n"), "{response}");
+
+        server.signal.trigger();
+        assert_eq!(Ok(()), handle.join().unwrap());
+    }
+
+    #[test]
+    fn graceful_shutdown_drains_queue() {
+        let mut server = test_server();
+        server.settings.workers = 1;
+        server.settings.queue_depth = 1;
+        let server = Arc::new(server);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server_cl = server.clone();
+        let handle =
+            thread::spawn(move || run(server_cl, vec![(Bound::Tcp(listener), Role::Public)]));
+
+        // The only worker waits on an idle kept-alive connection
+        let mut idle = TcpStream::connect(address).unwrap();
+        idle.write_all(b"GET /first HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = [0; 512];
+        let read_count = idle.read(&mut response).unwrap();
+        assert!(response[..read_count].starts_with(b"HTTP/1.1 303 See Other\r\n"));
+
+        // Request of a queued connection arrived before shutdown
+        let mut queued = TcpStream::connect(address).unwrap();
+        queued.write_all(b"GET /second HTTP/1.1\r\n\r\n").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        server.signal.trigger();
+
+        let mut response = String::new();
+        queued.read_to_string(&mut response).unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 303 See Other\r\n"),
+            "{response}"
+        );
+        assert!(response.contains("Connection: close\r\n"), "{response}");
+        assert_eq!(Ok(()), handle.join().unwrap());
+        assert_eq!(0, idle.read(&mut [0; 512]).unwrap());
+    }
+
+    #[test]
+    fn unix_socket() {
+        let server = Arc::new(test_server());
+        let path = env::temp_dir().join(format!("banger-{}-server.sock", process::id()));
+        let listener = UnixListener::bind(&path).unwrap();
+        let (server_cl, path_cl) = (server.clone(), path.clone());
+        let handle = thread::spawn(move || {
+            run(
+                server_cl,
+                vec![(Bound::Unix(listener, Some(path_cl)), Role::Public)],
+            )
+        });
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+        stream
+            .write_all(b"GET /health HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"));
+
+        server.signal.trigger();
+        assert_eq!(Ok(()), handle.join().unwrap());
+        // Socket file bound by the server is removed
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn multiple_listeners() {
+        let server = Arc::new(test_server());
+        let listeners = [
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+            TcpListener::bind("127.0.0.1:0").unwrap(),
+        ];
+        let addresses: Vec<_> = listeners
+            .iter()
+            .map(|listener| listener.local_addr().unwrap())
+            .collect();
+        let server_cl = server.clone();
+        let handle = thread::spawn(move || {
+            run(
+                server_cl,
+                listeners
+                    .into_iter()
+                    .map(|listener| (Bound::Tcp(listener), Role::Public))
+                    .collect(),
+            )
+        });
+
+        for address in addresses {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .write_all(b"GET /health HTTP/1.1\r\nConnection: close\r\n\r\n")
+                .unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 200 OK\r\n"), "{address}");
+        }
+
+        server.signal.trigger();
+        assert_eq!(Ok(()), handle.join().unwrap());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_busy() {
+        use crate::tls::tests::{temp_path, write_self_signed};
+        use crate::tls::{TlsListener, TlsSettings, server_config};
+
+        let (certificate, key) = (temp_path("busy-cert.pem"), temp_path("busy-key.pem"));
+        write_self_signed(&certificate, &key);
+        let settings = TlsSettings {
+            addresses: vec!["127.0.0.1:0".parse().unwrap()],
+            certificate: certificate.clone(),
+            key: key.clone(),
+        };
+        let listener =
+            TlsListener::bind(settings.addresses[0], server_config(&settings).unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut server = test_server();
+        server.settings.workers = 1;
+        server.settings.queue_depth = 0;
+        let server = Arc::new(server);
+        let server_cl = server.clone();
+        let handle =
+            thread::spawn(move || run(server_cl, vec![(Bound::Tls(listener), Role::Public)]));
+
+        // The only worker waits for the handshake of a silent client
+        let mut silent = TcpStream::connect(address).unwrap();
+        silent
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let err = silent.read(&mut [0; 16]).unwrap_err();
+        assert!(
+            matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+            "{err}"
+        );
+
+        // Rejected client is not waited for
+        let started = Instant::now();
+        let mut rejected = TcpStream::connect(address).unwrap();
+        rejected
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // Only a closing alert is sent, the handshake never starts
+        let mut response = Vec::new();
+        let _ = rejected.read_to_end(&mut response);
+        assert!(response.len() < 16, "{response:?}");
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        drop(silent);
+        server.signal.trigger();
+        assert_eq!(Ok(()), handle.join().unwrap());
+        fs::remove_file(certificate).unwrap();
+        fs::remove_file(key).unwrap();
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn tls_listener() {
+        use crate::tls::tests::{temp_path, write_self_signed};
+        use crate::tls::{TlsListener, TlsSettings, server_config};
+        use rustls::pki_types::ServerName;
+        use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+        let (certificate, key) = (temp_path("server-cert.pem"), temp_path("server-key.pem"));
+        let certificate_der = write_self_signed(&certificate, &key);
+        let settings = TlsSettings {
+            addresses: vec!["127.0.0.1:0".parse().unwrap()],
+            certificate: certificate.clone(),
+            key: key.clone(),
+        };
+        let listener =
+            TlsListener::bind(settings.addresses[0], server_config(&settings).unwrap()).unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = Arc::new(test_server());
+        let server_cl = server.clone();
+        let handle =
+            thread::spawn(move || run(server_cl, vec![(Bound::Tls(listener), Role::Public)]));
+
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate_der).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from("localhost").unwrap();
+        let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut stream = StreamOwned::new(connection, TcpStream::connect(address).unwrap());
+
+        // Two requests are sent in one record, the second one waits in the session
+        stream
+            .write_all(
+                b"GET /health HTTP/1.1\r\n\r\nGET /health HTTP/1.1\r\nConnection: close\r\n\r\n",
+            )
+            .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let response = String::from_utf8(response).unwrap();
+        assert_eq!(
+            2,
+            response.matches("HTTP/1.1 200 OK\r\n").count(),
+            "{response}"
+        );
+
+        server.signal.trigger();
+        assert_eq!(Ok(()), handle.join().unwrap());
+        fs::remove_file(certificate).unwrap();
+        fs::remove_file(key).unwrap();
     }
 }
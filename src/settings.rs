@@ -0,0 +1,427 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use toml::{Table, Value};
+
+use crate::access_log::LogFormat;
+use crate::listener::ListenAddr;
+use crate::log::Level;
+use crate::rate_limit::RateLimitSettings;
+
+/// Server settings, every one of them may be omitted from config
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Time a persistent connection may stay idle between requests
+    pub idle_timeout: Duration,
+    /// Time a client may take between reads once it started sending a request
+    pub read_timeout: Duration,
+    /// Time a client may take to send the whole head of a request
+    pub header_timeout: Duration,
+    /// Time a client may take to send the whole body of a request
+    pub body_timeout: Duration,
+    /// Time a client may take to accept each write of a response
+    pub write_timeout: Duration,
+    /// Time a connection may stay open, however busy it is
+    pub connection_timeout: Duration,
+    /// Longest accepted request line, in bytes
+    pub max_request_line: usize,
+    /// Longest accepted header block after the request line, in bytes
+    pub max_header_size: usize,
+    /// Largest accepted number of header lines
+    pub max_header_lines: usize,
+    /// Longest accepted request body, in bytes
+    pub max_body_size: usize,
+    /// Number of threads serving connections
+    pub workers: usize,
+    /// Number of accepted connections that may wait for a free worker
+    pub queue_depth: usize,
+    /// Time workers may take to finish their connections on shutdown
+    pub grace_period: Duration,
+    /// Query string parameter that holds search terms
+    pub query_param: String,
+    /// Field of `POST` form bodies that holds search terms
+    pub form_field: String,
+    /// URL the server is reachable at, listen address is used if it is not set
+    pub base_url: Option<String>,
+    /// Search engine name shown by browsers
+    pub short_name: String,
+    /// Permissions of the Unix socket file, umask decides if it is not set
+    pub socket_mode: Option<u32>,
+    /// File the access log is appended to, stderr is used if it is not set
+    pub access_log: Option<PathBuf>,
+    pub access_log_format: LogFormat,
+    /// Most detailed level of application log, unless `--log-level` or `BANGER_LOG` are set
+    pub log_level: Level,
+    /// Address `/metrics` is served on instead of the search addresses
+    pub metrics_address: Option<ListenAddr>,
+    /// Requests each client may send, not limited if it is not set
+    pub rate_limit: Option<RateLimitSettings>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            idle_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(10),
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            write_timeout: Duration::from_secs(10),
+            connection_timeout: Duration::from_secs(300),
+            max_request_line: 8192,
+            max_header_size: 16384,
+            max_header_lines: 100,
+            max_body_size: 65536,
+            workers: 16,
+            queue_depth: 64,
+            grace_period: Duration::from_secs(10),
+            query_param: "q".to_string(),
+            form_field: "q".to_string(),
+            base_url: None,
+            short_name: "Banger".to_string(),
+            socket_mode: None,
+            access_log: None,
+            access_log_format: LogFormat::Common,
+            log_level: Level::Info,
+            metrics_address: None,
+            rate_limit: None,
+        }
+    }
+}
+
+fn get_seconds(table: &Table, key: &str) -> Result<Option<Duration>, String> {
+    let seconds = match table.get(key) {
+        None => return Ok(None),
+        Some(Value::Integer(value)) => *value as f64,
+        Some(Value::Float(value)) => *value,
+        Some(value) => return Err(format!("`{key}` is not a number: {value}")),
+    };
+    match Duration::try_from_secs_f64(seconds) {
+        Ok(duration) if !duration.is_zero() => Ok(Some(duration)),
+        _ => Err(format!(
+            "`{key}` is not a positive number of seconds: {seconds}"
+        )),
+    }
+}
+
+fn get_count(table: &Table, key: &str, min: usize) -> Result<Option<usize>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::Integer(value)) => match usize::try_from(*value) {
+            Ok(count) if count >= min => Ok(Some(count)),
+            _ => Err(format!("`{key}` must be at least {min}: {value}")),
+        },
+        Some(value) => Err(format!("`{key}` is not an integer: {value}")),
+    }
+}
+
+fn get_string(table: &Table, key: &str) -> Result<Option<String>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::String(value)) if !value.is_empty() => Ok(Some(value.clone())),
+        Some(value) => Err(format!("`{key}` is not a non-empty string: {value}")),
+    }
+}
+
+fn get_url(table: &Table, key: &str) -> Result<Option<String>, String> {
+    match get_string(table, key)? {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+            Ok(Some(url.trim_end_matches('/').to_string()))
+        }
+        Some(url) => Err(format!("`{key}` is not an HTTP(S) URL: {url}")),
+        None => Ok(None),
+    }
+}
+
+fn get_mode(table: &Table, key: &str) -> Result<Option<u32>, String> {
+    match table.get(key) {
+        None => Ok(None),
+        Some(Value::Integer(value)) => match u32::try_from(*value) {
+            Ok(mode) if mode <= 0o777 => Ok(Some(mode)),
+            _ => Err(format!("`{key}` is not a permission mode: {value}")),
+        },
+        Some(value) => Err(format!("`{key}` is not an integer: {value}")),
+    }
+}
+
+fn get_log_path(table: &Table, key: &str) -> Result<Option<PathBuf>, String> {
+    match get_string(table, key)? {
+        Some(path) if path == "-" => Ok(None),
+        path => Ok(path.map(PathBuf::from)),
+    }
+}
+
+fn get_log_format(table: &Table, key: &str) -> Result<Option<LogFormat>, String> {
+    match get_string(table, key)? {
+        Some(format) => format.parse().map(Some).map_err(|_err| {
+            format!("`{key}` is not one of `common`, `combined`, `combined_ext`, `json`: {format}")
+        }),
+        None => Ok(None),
+    }
+}
+
+fn get_log_level(table: &Table, key: &str) -> Result<Option<Level>, String> {
+    match get_string(table, key)? {
+        Some(level) => level
+            .parse()
+            .map(Some)
+            .map_err(|err| format!("`{key}` is invalid: {err}")),
+        None => Ok(None),
+    }
+}
+
+fn get_address(table: &Table, key: &str) -> Result<Option<ListenAddr>, String> {
+    match get_string(table, key)? {
+        Some(address) => address
+            .parse()
+            .map(Some)
+            .map_err(|err| format!("`{key}` is invalid: {err}")),
+        None => Ok(None),
+    }
+}
+
+impl Settings {
+    pub fn from_table(table: &Table) -> Result<Settings, String> {
+        let default = Settings::default();
+        let query_param = get_string(table, "query_param")?.unwrap_or(default.query_param);
+        Ok(Settings {
+            idle_timeout: get_seconds(table, "idle_timeout")?.unwrap_or(default.idle_timeout),
+            read_timeout: get_seconds(table, "read_timeout")?.unwrap_or(default.read_timeout),
+            header_timeout: get_seconds(table, "header_timeout")?.unwrap_or(default.header_timeout),
+            body_timeout: get_seconds(table, "body_timeout")?.unwrap_or(default.body_timeout),
+            write_timeout: get_seconds(table, "write_timeout")?.unwrap_or(default.write_timeout),
+            connection_timeout: get_seconds(table, "connection_timeout")?
+                .unwrap_or(default.connection_timeout),
+            max_request_line: get_count(table, "max_request_line", 16)?
+                .unwrap_or(default.max_request_line),
+            max_header_size: get_count(table, "max_header_size", 0)?
+                .unwrap_or(default.max_header_size),
+            max_header_lines: get_count(table, "max_header_lines", 0)?
+                .unwrap_or(default.max_header_lines),
+            max_body_size: get_count(table, "max_body_size", 0)?.unwrap_or(default.max_body_size),
+            workers: get_count(table, "workers", 1)?.unwrap_or(default.workers),
+            queue_depth: get_count(table, "queue_depth", 0)?.unwrap_or(default.queue_depth),
+            grace_period: get_seconds(table, "grace_period")?.unwrap_or(default.grace_period),
+            // Forms usually name the field the same as the parameter
+            form_field: get_string(table, "form_field")?.unwrap_or(query_param.clone()),
+            query_param,
+            base_url: get_url(table, "base_url")?,
+            short_name: get_string(table, "short_name")?.unwrap_or(default.short_name),
+            socket_mode: get_mode(table, "socket_mode")?,
+            access_log: get_log_path(table, "access_log")?,
+            access_log_format: get_log_format(table, "access_log_format")?
+                .unwrap_or(default.access_log_format),
+            log_level: get_log_level(table, "log_level")?.unwrap_or(default.log_level),
+            metrics_address: get_address(table, "metrics_address")?,
+            rate_limit: RateLimitSettings::from_table(table)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let settings = Settings::from_table(&Table::new()).unwrap();
+        assert_eq!(Settings::default().idle_timeout, settings.idle_timeout);
+        assert_eq!(Settings::default().read_timeout, settings.read_timeout);
+        assert_eq!(Settings::default().header_timeout, settings.header_timeout);
+        assert_eq!(Settings::default().body_timeout, settings.body_timeout);
+        assert_eq!(Settings::default().write_timeout, settings.write_timeout);
+        assert_eq!(
+            Settings::default().connection_timeout,
+            settings.connection_timeout
+        );
+        assert_eq!(Settings::default().workers, settings.workers);
+        assert_eq!(Settings::default().queue_depth, settings.queue_depth);
+        assert_eq!(Settings::default().grace_period, settings.grace_period);
+        assert_eq!(Settings::default().query_param, settings.query_param);
+        assert_eq!(None, settings.base_url);
+        assert_eq!(Settings::default().short_name, settings.short_name);
+        assert_eq!(None, settings.socket_mode);
+        assert_eq!(None, settings.access_log);
+        assert_eq!(LogFormat::Common, settings.access_log_format);
+        assert_eq!(Level::Info, settings.log_level);
+        assert_eq!(None, settings.metrics_address);
+        assert_eq!(None, settings.rate_limit);
+    }
+
+    #[test]
+    fn metrics_address() {
+        let table: Table = "metrics_address = '127.0.0.1:9090'".parse().unwrap();
+        assert_eq!(
+            Some(ListenAddr::Tcp("127.0.0.1:9090".parse().unwrap())),
+            Settings::from_table(&table).unwrap().metrics_address
+        );
+        for content in ["metrics_address = 'localhost'", "metrics_address = 9090"] {
+            let table: Table = content.parse().unwrap();
+            assert!(Settings::from_table(&table).is_err(), "{content}");
+        }
+    }
+
+    #[test]
+    fn log_level() {
+        let table: Table = "log_level = 'debug'".parse().unwrap();
+        assert_eq!(
+            Level::Debug,
+            Settings::from_table(&table).unwrap().log_level
+        );
+        for content in ["log_level = 'verbose'", "log_level = 3"] {
+            let table: Table = content.parse().unwrap();
+            assert!(Settings::from_table(&table).is_err(), "{content}");
+        }
+    }
+
+    #[test]
+    fn access_log() {
+        const CONTENT: &str = "
+            access_log = '/var/log/banger/access.log'
+            access_log_format = 'json'";
+        let table: Table = CONTENT.parse().unwrap();
+        let settings = Settings::from_table(&table).unwrap();
+        assert_eq!(
+            Some(PathBuf::from("/var/log/banger/access.log")),
+            settings.access_log
+        );
+        assert_eq!(LogFormat::Json, settings.access_log_format);
+
+        let table: Table = "access_log = '-'".parse().unwrap();
+        assert_eq!(None, Settings::from_table(&table).unwrap().access_log);
+        for content in ["access_log = ''", "access_log_format = 'apache'"] {
+            let table: Table = content.parse().unwrap();
+            assert!(Settings::from_table(&table).is_err(), "{content}");
+        }
+    }
+
+    #[test]
+    fn socket_mode() {
+        let table: Table = "socket_mode = 0o660".parse().unwrap();
+        let settings = Settings::from_table(&table).unwrap();
+        assert_eq!(Some(0o660), settings.socket_mode);
+        for content in [
+            "socket_mode = 0o1777",
+            "socket_mode = -1",
+            "socket_mode = '660'",
+        ] {
+            let table: Table = content.parse().unwrap();
+            assert!(Settings::from_table(&table).is_err(), "{content}");
+        }
+    }
+
+    #[test]
+    fn opensearch() {
+        const CONTENT: &str = "
+            base_url = 'https://banger.example/'
+            short_name = 'Team bangs'";
+        let table: Table = CONTENT.parse().unwrap();
+        let settings = Settings::from_table(&table).unwrap();
+        assert_eq!(
+            Some("https://banger.example".to_string()),
+            settings.base_url
+        );
+        assert_eq!("Team bangs", settings.short_name);
+
+        let table: Table = "base_url = 'banger.example'".parse().unwrap();
+        assert!(Settings::from_table(&table).is_err());
+    }
+
+    #[test]
+    fn query_param() {
+        let table: Table = "query_param = 'terms'".parse().unwrap();
+        let settings = Settings::from_table(&table).unwrap();
+        assert_eq!("terms", settings.query_param);
+        assert_eq!("terms", settings.form_field);
+
+        let table: Table = "query_param = 'terms'\nform_field = 'search'"
+            .parse()
+            .unwrap();
+        let settings = Settings::from_table(&table).unwrap();
+        assert_eq!("search", settings.form_field);
+
+        for content in [
+            "query_param = ''",
+            "query_param = 1",
+            "form_field = ''",
+            "form_field = 1",
+        ] {
+            let table: Table = content.parse().unwrap();
+            assert!(Settings::from_table(&table).is_err(), "{content}");
+        }
+    }
+
+    #[test]
+    fn timeouts() {
+        const CONTENT: &str = "
+            idle_timeout = 2
+            read_timeout = 0.5
+            header_timeout = 3
+            body_timeout = 4
+            write_timeout = 1.5
+            connection_timeout = 60
+            grace_period = 30";
+        let table: Table = CONTENT.parse().unwrap();
+        let settings = Settings::from_table(&table).unwrap();
+        assert_eq!(Duration::from_secs(2), settings.idle_timeout);
+        assert_eq!(Duration::from_millis(500), settings.read_timeout);
+        assert_eq!(Duration::from_secs(3), settings.header_timeout);
+        assert_eq!(Duration::from_secs(4), settings.body_timeout);
+        assert_eq!(Duration::from_millis(1500), settings.write_timeout);
+        assert_eq!(Duration::from_secs(60), settings.connection_timeout);
+        assert_eq!(Duration::from_secs(30), settings.grace_period);
+    }
+
+    #[test]
+    fn pool() {
+        let table: Table = "workers = 2\nqueue_depth = 0".parse().unwrap();
+        let settings = Settings::from_table(&table).unwrap();
+        assert_eq!(2, settings.workers);
+        assert_eq!(0, settings.queue_depth);
+    }
+
+    #[test]
+    fn limits() {
+        const CONTENT: &str = "
+            max_request_line = 1024
+            max_header_size = 2048
+            max_header_lines = 20
+            max_body_size = 0";
+        let table: Table = CONTENT.parse().unwrap();
+        let settings = Settings::from_table(&table).unwrap();
+        assert_eq!(1024, settings.max_request_line);
+        assert_eq!(2048, settings.max_header_size);
+        assert_eq!(20, settings.max_header_lines);
+        assert_eq!(0, settings.max_body_size);
+        for content in [
+            "max_request_line = 8",
+            "max_body_size = -1",
+            "max_header_size = '1k'",
+            "max_header_lines = -1",
+        ] {
+            let table: Table = content.parse().unwrap();
+            assert!(Settings::from_table(&table).is_err(), "{content}");
+        }
+    }
+
+    #[test]
+    fn invalid_pool() {
+        for content in ["workers = 0", "workers = 'many'", "queue_depth = -1"] {
+            let table: Table = content.parse().unwrap();
+            assert!(Settings::from_table(&table).is_err(), "{content}");
+        }
+    }
+
+    #[test]
+    fn invalid_timeouts() {
+        for content in [
+            "idle_timeout = 'long'",
+            "read_timeout = 0",
+            "read_timeout = -1",
+            "header_timeout = 0",
+            "connection_timeout = 'forever'",
+        ] {
+            let table: Table = content.parse().unwrap();
+            assert!(Settings::from_table(&table).is_err(), "{content}");
+        }
+    }
+}
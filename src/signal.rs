@@ -0,0 +1,139 @@
+use std::io::{self, ErrorKind};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::low_level::pipe;
+
+#[derive(Debug, PartialEq)]
+pub enum Wait {
+    /// Watched descriptor has data or was closed
+    Ready,
+    /// Shutdown was requested
+    Shutdown,
+    /// Nothing happened in time
+    TimedOut,
+}
+
+/// Self-pipe that becomes readable once the server is asked to stop.
+/// Nothing ever reads from it, so every waiting thread is woken up.
+pub struct ShutdownSignal {
+    receiver: UnixStream,
+    sender: UnixStream,
+}
+
+impl ShutdownSignal {
+    pub fn new() -> io::Result<Self> {
+        let (sender, receiver) = UnixStream::pair()?;
+        Ok(ShutdownSignal { receiver, sender })
+    }
+
+    /// Triggers the signal on SIGTERM and SIGINT
+    pub fn register(&self) -> io::Result<()> {
+        for signal in [SIGTERM, SIGINT] {
+            pipe::register(signal, self.sender.try_clone()?)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub fn trigger(&self) {
+        use std::io::Write;
+        (&self.sender).write_all(&[0]).unwrap();
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        let mut fds = [pollfd(self.receiver.as_raw_fd())];
+        matches!(poll(&mut fds, Some(Duration::ZERO)), Ok(())) && fds[0].revents != 0
+    }
+
+    /// Waits until `fd` is readable or shutdown is requested.
+    /// Pending data takes priority over shutdown, so requests that arrived are still served.
+    /// Waits indefinitely if `timeout` is `None`.
+    pub fn wait(&self, fd: RawFd, timeout: Option<Duration>) -> io::Result<Wait> {
+        let mut fds = [pollfd(self.receiver.as_raw_fd()), pollfd(fd)];
+        poll(&mut fds, timeout)?;
+        if fds[1].revents != 0 {
+            Ok(Wait::Ready)
+        } else if fds[0].revents != 0 {
+            Ok(Wait::Shutdown)
+        } else {
+            Ok(Wait::TimedOut)
+        }
+    }
+}
+
+fn pollfd(fd: RawFd) -> libc::pollfd {
+    libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    }
+}
+
+fn poll(fds: &mut [libc::pollfd], timeout: Option<Duration>) -> io::Result<()> {
+    let timeout_ms = match timeout {
+        Some(timeout) => timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int,
+        None => -1,
+    };
+    loop {
+        // SAFETY: `fds` points to `fds.len()` initialized pollfd structures
+        let result = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms) };
+        if result >= 0 {
+            return Ok(());
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn ready() {
+        let signal = ShutdownSignal::new().unwrap();
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        sender.write_all(b"data").unwrap();
+        assert_eq!(
+            Wait::Ready,
+            signal.wait(receiver.as_raw_fd(), None).unwrap()
+        );
+        assert!(!signal.is_triggered());
+    }
+
+    #[test]
+    fn timed_out() {
+        let signal = ShutdownSignal::new().unwrap();
+        let (_sender, receiver) = UnixStream::pair().unwrap();
+        assert_eq!(
+            Wait::TimedOut,
+            signal
+                .wait(receiver.as_raw_fd(), Some(Duration::from_millis(10)))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn shutdown() {
+        let signal = ShutdownSignal::new().unwrap();
+        let (mut sender, receiver) = UnixStream::pair().unwrap();
+        signal.trigger();
+        assert!(signal.is_triggered());
+        assert_eq!(
+            Wait::Shutdown,
+            signal.wait(receiver.as_raw_fd(), None).unwrap()
+        );
+        // Pending data takes priority over shutdown
+        sender.write_all(b"data").unwrap();
+        assert_eq!(
+            Wait::Ready,
+            signal.wait(receiver.as_raw_fd(), None).unwrap()
+        );
+    }
+}
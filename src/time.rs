@@ -0,0 +1,124 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Calendar date and time in UTC
+struct CivilTime {
+    year: u64,
+    /// January is 0
+    month: usize,
+    day: u64,
+    hour: u64,
+    minute: u64,
+    second: u64,
+    /// Thursday is 0, as 1970-01-01 was Thursday
+    weekday: usize,
+}
+
+impl CivilTime {
+    fn new(time: SystemTime) -> Self {
+        let seconds = time
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0);
+        let (days, day_seconds) = (seconds / 86400, seconds % 86400);
+
+        // Civil date from days since epoch, see http://howardhinnant.github.io/date_algorithms.html
+        let shifted = days + 719468;
+        let era = shifted / 146097;
+        let day_of_era = shifted % 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153; // March-based
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = (month_index + 2) % 12; // January-based
+        let year = year_of_era + era * 400 + if month < 2 { 1 } else { 0 };
+
+        CivilTime {
+            year,
+            month: month as usize,
+            day,
+            hour: day_seconds / 3600,
+            minute: day_seconds % 3600 / 60,
+            second: day_seconds % 60,
+            weekday: (days % 7) as usize,
+        }
+    }
+}
+
+/// Formats time as HTTP-date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    let civil = CivilTime::new(time);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[civil.weekday],
+        civil.day,
+        MONTHS[civil.month],
+        civil.year,
+        civil.hour,
+        civil.minute,
+        civil.second
+    )
+}
+
+/// Formats time as in Common Log Format, e.g. `10/Oct/2000:13:55:36 +0000`
+pub fn clf_date(time: SystemTime) -> String {
+    let civil = CivilTime::new(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        civil.day, MONTHS[civil.month], civil.year, civil.hour, civil.minute, civil.second
+    )
+}
+
+/// Formats time as RFC 3339 timestamp, e.g. `2000-10-10T13:55:36Z`
+pub fn rfc3339(time: SystemTime) -> String {
+    let civil = CivilTime::new(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        civil.year,
+        civil.month + 1,
+        civil.day,
+        civil.hour,
+        civil.minute,
+        civil.second
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn http_date_success() {
+        const DATES: [(u64, &str); 4] = [
+            (0, "Thu, 01 Jan 1970 00:00:00 GMT"),
+            (951782400, "Tue, 29 Feb 2000 00:00:00 GMT"),
+            (1709251199, "Thu, 29 Feb 2024 23:59:59 GMT"),
+            (4102444800, "Fri, 01 Jan 2100 00:00:00 GMT"),
+        ];
+        for (seconds, expected) in DATES {
+            assert_eq!(
+                expected,
+                http_date(UNIX_EPOCH + Duration::from_secs(seconds))
+            );
+        }
+    }
+
+    #[test]
+    fn clf_date_success() {
+        let time = UNIX_EPOCH + Duration::from_secs(971186136);
+        assert_eq!("10/Oct/2000:13:55:36 +0000", clf_date(time));
+    }
+
+    #[test]
+    fn rfc3339_success() {
+        let time = UNIX_EPOCH + Duration::from_secs(971186136);
+        assert_eq!("2000-10-10T13:55:36Z", rfc3339(time));
+        assert_eq!("1970-01-01T00:00:00Z", rfc3339(UNIX_EPOCH));
+    }
+}
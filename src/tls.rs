@@ -0,0 +1,392 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use rustls::crypto::ring;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use toml::{Table, Value};
+
+use crate::listener::{Connection, Listener};
+use crate::log::{error, info};
+use crate::reader::TimedRead;
+
+/// Time between checks whether certificate files changed
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// HTTPS listener settings from the `tls` table
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsSettings {
+    pub addresses: Vec<SocketAddr>,
+    /// PEM file with the certificate chain, server certificate first
+    pub certificate: PathBuf,
+    /// PEM file with the private key
+    pub key: PathBuf,
+}
+
+fn get_path(table: &Table, key: &str) -> Result<PathBuf, String> {
+    match table.get(key) {
+        Some(Value::String(value)) if !value.is_empty() => Ok(PathBuf::from(value)),
+        Some(value) => Err(format!("`tls.{key}` is not a non-empty string: {value}")),
+        None => Err(format!("`tls.{key}` is missing")),
+    }
+}
+
+fn parse_address(value: &Value) -> Result<SocketAddr, String> {
+    value
+        .as_str()
+        .and_then(|address| address.parse().ok())
+        .ok_or(format!("`tls.address` is not a socket address: {value}"))
+}
+
+impl TlsSettings {
+    pub fn from_table(table: &Table) -> Result<Option<TlsSettings>, String> {
+        let tls = match table.get("tls") {
+            None => return Ok(None),
+            Some(Value::Table(tls)) => tls,
+            Some(value) => return Err(format!("`tls` is not a table: {value}")),
+        };
+        let addresses = match tls.get("address") {
+            None => return Err("`tls.address` is missing".to_string()),
+            Some(Value::Array(values)) if values.is_empty() => {
+                return Err("`tls.address` is empty".to_string());
+            }
+            Some(Value::Array(values)) => values
+                .iter()
+                .map(parse_address)
+                .collect::<Result<Vec<_>, _>>()?,
+            Some(value) => vec![parse_address(value)?],
+        };
+        Ok(Some(TlsSettings {
+            addresses,
+            certificate: get_path(tls, "certificate")?,
+            key: get_path(tls, "key")?,
+        }))
+    }
+}
+
+fn load_certified_key(certificate: &Path, key: &Path) -> Result<CertifiedKey, String> {
+    let chain = CertificateDer::pem_file_iter(certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| format!("{}: {err}", certificate.display()))?;
+    if chain.is_empty() {
+        return Err(format!("{}: no certificates found", certificate.display()));
+    }
+    let key_der =
+        PrivateKeyDer::from_pem_file(key).map_err(|err| format!("{}: {err}", key.display()))?;
+    let signing_key = ring::sign::any_supported_type(&key_der)
+        .map_err(|err| format!("{}: {err}", key.display()))?;
+    let certified_key = CertifiedKey::new(chain, signing_key);
+    certified_key.keys_match().map_err(|err| {
+        format!(
+            "{} does not match {}: {err}",
+            key.display(),
+            certificate.display()
+        )
+    })?;
+    Ok(certified_key)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[derive(Debug)]
+struct ResolverState {
+    current: Arc<CertifiedKey>,
+    /// Modification times of certificate and key files the last time they were checked
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    checked: Instant,
+}
+
+/// Gives the certificate from files to every handshake, reloading it once the files change
+#[derive(Debug)]
+struct CertificateResolver {
+    certificate: PathBuf,
+    key: PathBuf,
+    interval: Duration,
+    state: Mutex<ResolverState>,
+}
+
+impl CertificateResolver {
+    fn new(certificate: &Path, key: &Path, interval: Duration) -> Result<Self, String> {
+        let modified = (modified(certificate), modified(key));
+        let current = load_certified_key(certificate, key)?;
+        Ok(CertificateResolver {
+            certificate: certificate.to_path_buf(),
+            key: key.to_path_buf(),
+            interval,
+            state: Mutex::new(ResolverState {
+                current: Arc::new(current),
+                modified,
+                checked: Instant::now(),
+            }),
+        })
+    }
+
+    fn current(&self) -> Arc<CertifiedKey> {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        if state.checked.elapsed() >= self.interval {
+            state.checked = Instant::now();
+            let modified = (modified(&self.certificate), modified(&self.key));
+            if modified != state.modified {
+                // Files are not retried until they change again, a half-written pair
+                // is picked up once the second file is written
+                state.modified = modified;
+                match load_certified_key(&self.certificate, &self.key) {
+                    Ok(certified_key) => {
+                        info!("Reloaded certificate", path = self.certificate.display());
+                        state.current = Arc::new(certified_key);
+                    }
+                    Err(err) => {
+                        error!(
+                            "Failed to reload certificate, keeping the previous one",
+                            error = err
+                        )
+                    }
+                }
+            }
+        }
+        state.current.clone()
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.current())
+    }
+}
+
+fn server_config_with(resolver: CertificateResolver) -> Result<Arc<ServerConfig>, String> {
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .map_err(|err| format!("Failed to set up TLS: {err}"))?
+        .with_no_client_auth()
+        .with_cert_resolver(Arc::new(resolver));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+/// Loads the certificate, shared by all HTTPS listeners
+pub fn server_config(settings: &TlsSettings) -> Result<Arc<ServerConfig>, String> {
+    let resolver = CertificateResolver::new(&settings.certificate, &settings.key, RELOAD_INTERVAL)?;
+    server_config_with(resolver)
+}
+
+/// Listener that encrypts accepted connections
+pub struct TlsListener {
+    listener: TcpListener,
+    config: Arc<ServerConfig>,
+}
+
+impl TlsListener {
+    pub fn bind(address: SocketAddr, config: Arc<ServerConfig>) -> Result<TlsListener, String> {
+        let listener = TcpListener::bind(address).map_err(|err| format!("{address}: {err}"))?;
+        Ok(TlsListener { listener, config })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl AsRawFd for TlsListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Listener for TlsListener {
+    type Stream = TlsStream;
+
+    /// Accepts a connection, the handshake happens on the first read by a worker
+    fn accept(&self) -> io::Result<TlsStream> {
+        let (stream, _peer) = self.listener.accept()?;
+        let connection = ServerConnection::new(self.config.clone()).map_err(io::Error::other)?;
+        Ok(TlsStream(StreamOwned::new(connection, stream)))
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.listener.set_nonblocking(nonblocking)
+    }
+}
+
+/// Encrypted connection
+pub struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl AsRawFd for TlsStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.sock.as_raw_fd()
+    }
+}
+
+impl TimedRead for TlsStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_read_timeout(timeout)
+    }
+}
+
+impl Connection for TlsStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.0.sock.set_nonblocking(nonblocking)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.0.sock.set_write_timeout(timeout)
+    }
+
+    fn shutdown(&mut self) -> io::Result<()> {
+        self.0.conn.send_close_notify();
+        // Client may be gone already, the socket is shut down anyway
+        let _ = self.0.flush();
+        self.0.sock.shutdown(Shutdown::Both)
+    }
+
+    fn peer_ip(&self) -> Option<IpAddr> {
+        self.0.sock.peer_ip()
+    }
+
+    /// Decrypted data may wait in the session while the socket has nothing to read
+    fn has_pending(&mut self) -> bool {
+        match self.0.conn.process_new_packets() {
+            Ok(state) => state.plaintext_bytes_to_read() > 0,
+            // Let the next read report the error
+            Err(_err) => true,
+        }
+    }
+
+    fn is_handshaking(&self) -> bool {
+        self.0.conn.is_handshaking()
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use std::time::UNIX_EPOCH;
+
+    /// Writes a self-signed certificate for `localhost` and its key,
+    /// returns the certificate in DER format
+    pub fn write_self_signed(certificate: &Path, key: &Path) -> CertificateDer<'static> {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        fs::write(certificate, generated.cert.pem()).unwrap();
+        fs::write(key, generated.signing_key.serialize_pem()).unwrap();
+        generated.cert.der().clone()
+    }
+
+    pub fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("banger-{}-{name}", process::id()))
+    }
+
+    #[test]
+    fn settings() {
+        const CONTENT: &str = "
+            [tls]
+            address = ['127.0.0.1:8443', '[::1]:8443']
+            certificate = '/etc/banger/cert.pem'
+            key = '/etc/banger/key.pem'";
+        let table: Table = CONTENT.parse().unwrap();
+        let settings = TlsSettings::from_table(&table).unwrap().unwrap();
+        assert_eq!(2, settings.addresses.len());
+        assert_eq!(PathBuf::from("/etc/banger/cert.pem"), settings.certificate);
+        assert_eq!(PathBuf::from("/etc/banger/key.pem"), settings.key);
+
+        assert_eq!(None, TlsSettings::from_table(&Table::new()).unwrap());
+    }
+
+    #[test]
+    fn invalid_settings() {
+        for content in [
+            "tls = 1",
+            "[tls]\ncertificate = 'c'\nkey = 'k'",
+            "[tls]\naddress = []\ncertificate = 'c'\nkey = 'k'",
+            "[tls]\naddress = 'localhost'\ncertificate = 'c'\nkey = 'k'",
+            "[tls]\naddress = '127.0.0.1:8443'\nkey = 'k'",
+            "[tls]\naddress = '127.0.0.1:8443'\ncertificate = ''\nkey = 'k'",
+        ] {
+            let table: Table = content.parse().unwrap();
+            assert!(TlsSettings::from_table(&table).is_err(), "{content}");
+        }
+    }
+
+    #[test]
+    fn chain() {
+        let (first, second, key) = (
+            temp_path("chain-first.pem"),
+            temp_path("chain-second.pem"),
+            temp_path("chain-key.pem"),
+        );
+        let first_der = write_self_signed(&first, &key);
+        let second_der = write_self_signed(&second, &temp_path("chain-unused.pem"));
+        let chain = temp_path("chain.pem");
+        let content = fs::read_to_string(&first).unwrap() + &fs::read_to_string(&second).unwrap();
+        fs::write(&chain, content).unwrap();
+
+        let certified_key = load_certified_key(&chain, &key).unwrap();
+        assert_eq!(vec![first_der, second_der], certified_key.cert);
+
+        // Key must belong to the first certificate
+        assert!(load_certified_key(&second, &key).is_err());
+        assert!(load_certified_key(&key, &key).is_err());
+        for path in [first, second, key, chain, temp_path("chain-unused.pem")] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn reload() {
+        let (certificate, key) = (temp_path("reload-cert.pem"), temp_path("reload-key.pem"));
+        let first = write_self_signed(&certificate, &key);
+        let resolver = CertificateResolver::new(&certificate, &key, Duration::ZERO).unwrap();
+        assert_eq!(first, resolver.current().cert[0]);
+
+        // Modification times are set explicitly, as they may be coarser than the test
+        let set_modified = |path: &Path, seconds| {
+            let file = fs::File::options().write(true).open(path).unwrap();
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(seconds))
+                .unwrap();
+        };
+
+        // Broken files keep the previous certificate
+        fs::write(&key, "broken").unwrap();
+        set_modified(&key, 1_000_000);
+        assert_eq!(first, resolver.current().cert[0]);
+
+        let second = write_self_signed(&certificate, &key);
+        set_modified(&certificate, 2_000_000);
+        set_modified(&key, 2_000_000);
+        assert_eq!(second, resolver.current().cert[0]);
+        fs::remove_file(certificate).unwrap();
+        fs::remove_file(key).unwrap();
+    }
+}